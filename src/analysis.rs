@@ -0,0 +1,472 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::energy;
+use crate::key::{self, Camelot};
+use crate::loudness::{self, Loudness};
+use crate::media_files;
+use crate::tempo::{self, Tempo};
+
+/// Audio quieter than this counts as silence.
+const SILENCE_NOISE: &str = "-50dB";
+/// Shortest silence, black or frozen stretch in seconds worth skipping.
+const MIN_GAP: f64 = 1.;
+/// Intervals this close to the start or end of a file touch it.
+const EDGE: f64 = 0.5;
+/// Longest black or frozen stretch at the start or end that is cut while the
+/// music goes on, e.g. a title card. Longer ones are part of the track, like
+/// a still picture over the outro.
+const MAX_PICTURE_ONLY: f64 = 10.;
+
+/// Cue points suggested by [`analyze_file`].
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct CuePoints {
+    pub cue_in: f64,
+    pub cue_out: Option<f64>,
+}
+
+/// Everything [`analyze_file`] found out about one file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    pub cue_points: CuePoints,
+    pub loudness: Option<Loudness>,
+    pub tempo: Option<Tempo>,
+    pub key: Option<Camelot>,
+}
+
+/// A silent, black or frozen stretch; `end` is `None` if it lasts until the
+/// end of the file.
+#[derive(Debug, Clone, Copy)]
+struct Interval {
+    start: f64,
+    end: Option<f64>,
+}
+
+/// Suggests cue points and detects tempo and key for every file of the library
+/// in `music_dir` that has none yet and stores them in `media-files.csv`,
+/// and measures the loudness of every file that is not in the loudness cache
+/// yet. Files without an energy rating get one estimated from tempo and
+/// loudness, and files without artist or title get them from the file name
+/// or the container tags. Each file
+/// is stored as soon as it is analysed, so an interrupted run can simply be
+/// started again. A file is decoded only once: what the analysis could not
+/// find is not searched for again unless its `analyzed` column is cleared.
+pub fn analyze_library(music_dir: &Path, data_dir: &Path) -> Result<(), Box<dyn Error>> {
+    let library = media_files::load(music_dir);
+    let known = media_files::read_media_files(data_dir)?;
+    let measured = loudness::read_cache(data_dir)?;
+
+    for (n, media_file) in library.iter().enumerate() {
+        let path = &media_file.path;
+        let Some(known) = known.iter().find(|known| known.path == *path) else {
+            eprintln!("{} is not in media-files.csv, skipping it", path.display());
+            continue;
+        };
+        let needs = |missing: bool| missing && !known.analyzed;
+        let needs_cue_points = needs(known.cue_in.is_none());
+        let needs_tempo = needs(known.bpm.is_none());
+        let needs_key = needs(known.key.is_none());
+        let (artist, title) = if needs(known.artist.is_none() || known.title.is_none()) {
+            artist_and_title(path)
+        } else {
+            (None, None)
+        };
+
+        let known_loudness = measured.iter().find(|loudness| loudness.path == *path);
+        let needs_loudness = needs(known_loudness.is_none());
+        let needs_energy = known.energy.is_none();
+
+        let analysis = if needs_cue_points || needs_tempo || needs_key || needs_loudness {
+            eprintln!(
+                "[{}/{}] analysing {} ...",
+                n + 1,
+                library.len(),
+                path.display()
+            );
+            analyze_file(path)
+                .inspect_err(|e| eprintln!("Cannot analyse {}: {e}", path.display()))
+                .ok()
+        } else {
+            None
+        };
+
+        let cue_points = analysis
+            .as_ref()
+            .filter(|_| needs_cue_points)
+            .map(|analysis| analysis.cue_points);
+        if let Some(cue_points) = cue_points {
+            eprintln!(
+                "cue-in: {:.1}, cue-out: {}",
+                cue_points.cue_in,
+                cue_points
+                    .cue_out
+                    .map_or("end of file".to_string(), |t| format!("{t:.1}"))
+            );
+        }
+
+        let tempo = match analysis.as_ref().map(|analysis| analysis.tempo) {
+            Some(Some(tempo)) if needs_tempo => {
+                eprintln!(
+                    "tempo: {:.1} BPM, first beat at {:.2}",
+                    tempo.bpm, tempo.first_beat
+                );
+                Some(tempo)
+            }
+            Some(None) => {
+                eprintln!("no steady beat found");
+                None
+            }
+            _ => None,
+        };
+
+        let key = match analysis.as_ref().map(|analysis| analysis.key) {
+            Some(Some(key)) if needs_key => {
+                eprintln!("key: {key}");
+                Some(key)
+            }
+            Some(None) => {
+                eprintln!("no key found");
+                None
+            }
+            _ => None,
+        };
+
+        match analysis.as_ref().map(|analysis| &analysis.loudness) {
+            Some(Some(loudness)) if needs_loudness => {
+                eprintln!(
+                    "loudness: {:.1} LUFS, true peak: {:.1} dBTP",
+                    loudness.integrated, loudness.true_peak
+                );
+                loudness::store(data_dir, loudness.clone())?;
+            }
+            Some(None) => eprintln!("mpv did not report the loudness"),
+            _ => {}
+        }
+
+        let energy = if needs_energy {
+            let bpm = known.bpm.or(tempo.map(|tempo| tempo.bpm));
+            let loudness = known_loudness.or(analysis
+                .as_ref()
+                .and_then(|analysis| analysis.loudness.as_ref()));
+            estimate_energy(path, bpm, loudness)
+        } else {
+            None
+        };
+
+        if analysis.is_none() && artist.is_none() && title.is_none() && energy.is_none() {
+            continue;
+        }
+
+        // Everything in one go, media-files.csv is rewritten once per file
+        media_files::update_media_file(data_dir, path, |media| {
+            if let Some(cue_points) = cue_points {
+                media.cue_in = Some(cue_points.cue_in);
+                media.cue_out = cue_points.cue_out;
+            }
+            if let Some(tempo) = tempo {
+                media.bpm = Some(tempo.bpm);
+                media.first_beat = Some(tempo.first_beat);
+            }
+            if let Some(key) = key {
+                media.key = Some(key);
+            }
+            if let Some(energy) = energy {
+                media.energy = Some(energy);
+            }
+            media.artist = media.artist.take().or(artist.clone());
+            media.title = media.title.take().or(title.clone());
+            media.analyzed |= analysis.is_some();
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Estimates the energy of the file from its tempo and loudness.
+fn estimate_energy(path: &Path, bpm: Option<f64>, loudness: Option<&Loudness>) -> Option<f64> {
+    let energy = energy::estimate(bpm, loudness)?;
+    eprintln!("energy of {}: {energy:.1}", path.display());
+
+    Some(energy)
+}
+
+/// Artist and title from a file name like `Artist - Title.mp4`, else from
+/// the tags of the file, as far as either has them.
+fn artist_and_title(path: &Path) -> (Option<String>, Option<String>) {
+    let (artist, title) = match media_files::artist_and_title(path) {
+        Some((artist, title)) => (Some(artist), Some(title)),
+        None => match read_tags(path) {
+            Ok(tags) => tags,
+            Err(e) => {
+                eprintln!("Cannot read the tags of {}: {e}", path.display());
+                return (None, None);
+            }
+        },
+    };
+
+    if artist.is_some() || title.is_some() {
+        eprintln!(
+            "{}: artist {}, title {}",
+            path.display(),
+            artist.as_deref().unwrap_or("unknown"),
+            title.as_deref().unwrap_or("unknown")
+        );
+    }
+
+    (artist, title)
+}
+
+/// Artist and title from the container tags, as far as the file has them.
+fn read_tags(path: &Path) -> Result<(Option<String>, Option<String>), Box<dyn Error>> {
+    let output = Command::new("mpv")
+        .arg("--no-config")
+        .arg("--vo=null")
+        .arg("--ao=null")
+        .arg("--frames=1")
+        .arg("--idle=no")
+        .arg("--force-window=no")
+        .arg("--msg-level=all=error,cplayer=info")
+        .arg("--term-playing-msg=mpv-dj-artist=${metadata/by-key/artist:}\nmpv-dj-title=${metadata/by-key/title:}")
+        .arg(path)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(format!("mpv exited: {}", output.status).into());
+    }
+
+    let log = String::from_utf8_lossy(&output.stdout).into_owned()
+        + &String::from_utf8_lossy(&output.stderr);
+    let tag = |key: &str| {
+        log.lines()
+            .find_map(|line| line.trim().strip_prefix(key))
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+    };
+
+    Ok((tag("mpv-dj-artist="), tag("mpv-dj-title=")))
+}
+
+/// Decodes the file with a headless mpv, as fast as possible and without
+/// any output. Looks for silence at both ends of the audio and for black
+/// frames or a still picture at both ends of the video, measures the
+/// loudness after EBU R128 and detects tempo and key of the decoded audio.
+pub fn analyze_file(path: &Path) -> Result<Analysis, Box<dyn Error>> {
+    let pcm_path = std::env::temp_dir().join(format!("mpv-dj-{}.pcm", std::process::id()));
+
+    let output = Command::new("mpv")
+        .arg("--no-config")
+        .arg("--vo=null")
+        // Raw mono samples for the tempo detection, written as fast as
+        // mpv can decode
+        .arg("--ao=pcm")
+        .arg(format!("--ao-pcm-file={}", pcm_path.display()))
+        .arg("--ao-pcm-waveheader=no")
+        .arg("--audio-format=s16")
+        .arg("--audio-channels=mono")
+        .arg(format!("--audio-samplerate={}", tempo::SAMPLE_RATE))
+        .arg("--untimed")
+        .arg("--idle=no")
+        .arg("--force-window=no")
+        .arg(format!(
+            "--af=lavfi=[silencedetect=n={SILENCE_NOISE}:d={MIN_GAP},ebur128=peak=true]"
+        ))
+        .arg(format!(
+            "--vf=lavfi=[blackdetect=d={MIN_GAP},freezedetect=d={MIN_GAP}]"
+        ))
+        // libavfilter reports its findings at mpv's verbose level
+        .arg("--msg-level=all=error,ffmpeg=v,cplayer=info")
+        .arg("--term-playing-msg=mpv-dj-duration=${=duration}")
+        .arg(path)
+        .output()?;
+
+    let pcm = fs::read(&pcm_path).unwrap_or_default();
+    let _ = fs::remove_file(&pcm_path);
+
+    if !output.status.success() {
+        return Err(format!("mpv exited: {}", output.status).into());
+    }
+
+    let samples: Vec<i16> = pcm
+        .chunks_exact(2)
+        .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+        .collect();
+
+    let log = String::from_utf8_lossy(&output.stdout).into_owned()
+        + &String::from_utf8_lossy(&output.stderr);
+
+    let mut duration = None;
+    let mut silence = Vec::new();
+    let mut picture = Vec::new();
+    // The loudness summary comes last, each value below its heading
+    let mut section = "";
+    let mut integrated = None;
+    let mut true_peak = None;
+    for line in log.lines() {
+        if line.contains("Integrated loudness:") {
+            section = "integrated";
+        } else if line.contains("True peak:") {
+            section = "true peak";
+        } else if section == "integrated"
+            && let Some(value) = value_after(line, "I")
+        {
+            integrated = Some(value);
+        } else if section == "true peak"
+            && let Some(value) = value_after(line, "Peak")
+        {
+            true_peak = Some(value);
+        }
+
+        if let Some(value) = line.trim().strip_prefix("mpv-dj-duration=") {
+            duration = value.trim().parse::<f64>().ok();
+        }
+
+        collect(line, "silence_start", "silence_end", &mut silence);
+        collect(line, "black_start", "black_end", &mut picture);
+        collect(line, "freeze_start", "freeze_end", &mut picture);
+    }
+    let duration = duration.ok_or("mpv did not report a duration")?;
+
+    let loudness = match (integrated, true_peak) {
+        (Some(integrated), Some(true_peak)) => Some(Loudness {
+            path: path.to_path_buf(),
+            integrated,
+            true_peak,
+        }),
+        _ => None,
+    };
+
+    Ok(Analysis {
+        cue_points: suggest(duration, &silence, &picture),
+        loudness,
+        tempo: tempo::detect(&samples),
+        key: key::detect(&samples),
+    })
+}
+
+/// Opens an interval at `start_key` and closes the last open one at
+/// `end_key`. Both may appear on the same line.
+fn collect(line: &str, start_key: &str, end_key: &str, intervals: &mut Vec<Interval>) {
+    if let Some(start) = value_after(line, start_key) {
+        intervals.push(Interval { start, end: None });
+    }
+    if let Some(end) = value_after(line, end_key)
+        && let Some(open) = intervals.last_mut()
+        && open.end.is_none()
+    {
+        open.end = Some(end);
+    }
+}
+
+/// The number after `key:` in a filter log line.
+fn value_after(line: &str, key: &str) -> Option<f64> {
+    let rest = &line[line.find(&format!("{key}:"))? + key.len() + 1..];
+    let number: String = rest
+        .trim_start()
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.' || *c == '-')
+        .collect();
+
+    number.parse().ok()
+}
+
+/// Cue-in after every stretch of silence or still picture at the start,
+/// cue-out before every one at the end. A still picture over music counts
+/// only up to [`MAX_PICTURE_ONLY`].
+fn suggest(duration: f64, silence: &[Interval], picture: &[Interval]) -> CuePoints {
+    let picture: Vec<&Interval> = picture
+        .iter()
+        .filter(|interval| interval.end.unwrap_or(duration) - interval.start <= MAX_PICTURE_ONLY)
+        .collect();
+    let intervals = || silence.iter().chain(picture.iter().copied());
+
+    let cue_in = intervals()
+        .filter(|interval| interval.start <= EDGE)
+        .filter_map(|interval| interval.end)
+        .fold(0., f64::max);
+
+    let cue_out = intervals()
+        .filter(|interval| interval.end.is_none_or(|end| end >= duration - EDGE))
+        .map(|interval| interval.start)
+        .filter(|&start| start > cue_in)
+        .reduce(f64::min);
+
+    CuePoints { cue_in, cue_out }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interval(start: f64, end: Option<f64>) -> Interval {
+        Interval { start, end }
+    }
+
+    #[test]
+    fn analysed_file_is_completed_without_decoding_it() {
+        let dir = std::env::temp_dir().join(format!("mpv-dj-analysis-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let music_dir = dir.join("music");
+        fs::create_dir_all(music_dir.join("pop")).unwrap();
+        fs::write(music_dir.join("pop").join("Abba - Waterloo.mp4"), "").unwrap();
+
+        // Decoded before, when neither key nor loudness could be found
+        let media_files: Vec<_> = media_files::load(&music_dir)
+            .into_iter()
+            .map(|media| media_files::MediaFile {
+                cue_in: Some(0.),
+                bpm: Some(128.),
+                analyzed: true,
+                ..media
+            })
+            .collect();
+        media_files::write_media_files_to_csv(
+            &media_files,
+            &dir.join(media_files::MEDIA_FILES_CSV),
+            &dir.join(media_files::CATEGORIES_CSV),
+        )
+        .unwrap();
+
+        analyze_library(&music_dir, &dir).unwrap();
+
+        let [media] = <[_; 1]>::try_from(media_files::read_media_files(&dir).unwrap()).unwrap();
+        assert_eq!(media.artist.as_deref(), Some("Abba"));
+        assert_eq!(media.title.as_deref(), Some("Waterloo"));
+        assert_eq!(media.energy, energy::estimate(Some(128.), None));
+        assert_eq!(media.key, None);
+        assert!(media.analyzed);
+        assert!(loudness::read_cache(&dir).unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn silence_and_short_title_cards_are_cut() {
+        let silence = [interval(0., Some(2.)), interval(178., None)];
+        let picture = [interval(0., Some(6.)), interval(171., None)];
+
+        assert_eq!(
+            suggest(180., &silence, &picture),
+            CuePoints {
+                cue_in: 6.,
+                cue_out: Some(171.)
+            }
+        );
+    }
+
+    #[test]
+    fn long_still_picture_over_music_is_kept() {
+        let silence = [interval(178., None)];
+        let picture = [interval(0., Some(40.)), interval(120., None)];
+
+        assert_eq!(
+            suggest(180., &silence, &picture),
+            CuePoints {
+                cue_in: 0.,
+                cue_out: Some(178.)
+            }
+        );
+    }
+}
@@ -1,148 +1,632 @@
-use std::io::{BufRead, BufReader, Write};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, BufReader};
+use std::net::{TcpStream, ToSocketAddrs};
 use std::os::unix::net::UnixStream;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use std::{thread, time::Duration};
+use std::time::{Duration, Instant};
 
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use serde_json::json;
 
-fn _full_screen(socket_path: &str, screen: usize) -> Result<(), String> {
-    let msg = json!({ "command": ["set_property", "fullscreen", false] });
-    send_msg(&socket_path, msg)?;
+use crate::error::MpvError;
+use crate::transport::Transport;
 
-    let msg = json!({ "command": ["set_property", "fs-screen", screen] });
-    send_msg(&socket_path, msg)?;
+/// How long a command may wait for its reply unless configured otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long mpv may take to load a file unless configured otherwise.
+pub const DEFAULT_LOAD_TIMEOUT: Duration = Duration::from_secs(20);
 
-    let msg = json!({ "command": ["set_property", "fullscreen", true] });
-    send_msg(&socket_path, msg)?;
-
-    Ok(())
+/// Asynchronous notification sent by mpv on the IPC socket.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MpvEvent {
+    FileLoaded,
+    EndFile {
+        reason: String,
+    },
+    Idle,
+    PropertyChange {
+        name: String,
+        data: serde_json::Value,
+    },
+    Other(String),
 }
 
-pub fn quit(socket_path: &str) -> Result<(), String> {
-    let msg = json!({ "command": ["quit"] });
-    let result = send_msg(&socket_path, msg)?;
-    eprintln!("{result:#}");
+impl MpvEvent {
+    fn from_json(value: &serde_json::Value) -> Option<Self> {
+        let event = match value.get("event")?.as_str()? {
+            "file-loaded" => MpvEvent::FileLoaded,
+            "end-file" => MpvEvent::EndFile {
+                reason: value
+                    .get("reason")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("unknown")
+                    .to_string(),
+            },
+            "idle" => MpvEvent::Idle,
+            "property-change" => MpvEvent::PropertyChange {
+                name: value.get("name")?.as_str()?.to_string(),
+                data: value.get("data").cloned().unwrap_or_default(),
+            },
+            other => MpvEvent::Other(other.to_string()),
+        };
 
-    Ok(())
+        Some(event)
+    }
 }
 
-pub fn get_video_path(socket_path: &str) -> Option<String> {
-    let msg = json!({ "command": ["get_property", "path"] });
-    let response = send_msg(socket_path, msg).ok()?;
+/// Why [`MpvClient::wait_for_the_end`] returned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrackEnd {
+    /// The requested number of seconds is left, time to mix in the next track.
+    TimeToMix,
+    /// The file ended (or mpv went idle) before the mix point was reached.
+    FileEnded,
+    /// The connection to mpv broke, the deck has probably crashed.
+    Disconnected,
+    /// The caller asked to stop waiting.
+    Interrupted,
+}
 
-    response.get("data").map(|v| v.to_string())
+/// One IPC connection to an mpv instance.
+///
+/// Every command is tagged with a `request_id` so that the reply can be told
+/// apart from asynchronous event lines mpv writes to the same socket. Events
+/// are queued until [`MpvClient::next_event`] picks them up, and the values
+/// of observed properties are cached as they arrive.
+///
+/// Each command has to be answered within the configured timeout, so a hung
+/// mpv surfaces as [`MpvError::Timeout`] instead of blocking forever.
+///
+/// The protocol is independent of how the bytes travel, see [`Transport`].
+/// Decks use a boxed one, so local and remote decks look the same.
+pub struct MpvClient<T: Transport = Box<dyn Transport + Send>> {
+    /// Socket path or address, used in log lines.
+    name: String,
+    stream: BufReader<T>,
+    timeout: Duration,
+    load_timeout: Duration,
+    next_request_id: u64,
+    line: Vec<u8>,
+    events: VecDeque<MpvEvent>,
+    next_observe_id: u64,
+    observed: HashMap<String, serde_json::Value>,
 }
 
-fn set_volume(socket_path: &str, volume: u8) -> Result<(), String> {
-    let msg = json!({ "command": ["set_property", "volume", volume] });
-    let _result = send_msg(&socket_path, msg)?;
-    Ok(())
+impl MpvClient {
+    pub fn connect(socket_path: &str) -> Result<Self, MpvError> {
+        let stream = UnixStream::connect(socket_path).map_err(MpvError::Connect)?;
+        Self::new(Box::new(stream), socket_path)
+    }
+
+    /// Connects to an IPC socket that is bridged to TCP on a remote machine.
+    pub fn connect_tcp(address: impl ToSocketAddrs + ToString) -> Result<Self, MpvError> {
+        let stream = TcpStream::connect(&address).map_err(MpvError::Connect)?;
+        Self::new(Box::new(stream), &address.to_string())
+    }
 }
 
-pub fn get_playback_time(socket_path: &str) -> Option<f64> {
-    let msg = json!({
-        "command": ["get_property", "time-pos"],
-    });
+impl<T: Transport> MpvClient<T> {
+    /// Wraps an established connection and subscribes to the properties the
+    /// client tracks itself.
+    pub fn new(transport: T, name: &str) -> Result<Self, MpvError> {
+        let mut client = Self {
+            name: name.to_string(),
+            stream: BufReader::new(transport),
+            timeout: DEFAULT_TIMEOUT,
+            load_timeout: DEFAULT_LOAD_TIMEOUT,
+            next_request_id: 1,
+            line: Vec::new(),
+            events: VecDeque::new(),
+            next_observe_id: 1,
+            observed: HashMap::new(),
+        };
 
-    let response = send_msg(socket_path, msg).ok()?;
+        client.observe("time-pos")?;
+        client.observe("duration")?;
 
-    response.get("data").map(|v| v.as_f64())?
-}
+        Ok(client)
+    }
 
-pub fn get_duration(socket_path: &str) -> Option<f64> {
-    let msg = json!({
-        "command": ["get_property", "duration"],
-    });
+    /// Sets the deadline for the reply to every following command.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sets the deadline for every following file to be loaded.
+    pub fn set_load_timeout(&mut self, timeout: Duration) {
+        self.load_timeout = timeout;
+    }
 
-    let response = send_msg(socket_path, msg).ok()?;
+    pub fn command(&mut self, command: serde_json::Value) -> Result<serde_json::Value, MpvError> {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
 
-    response.get("data").map(|v| v.as_f64())?
-}
+        let name = command
+            .get(0)
+            .and_then(|v| v.as_str())
+            .unwrap_or("command")
+            .to_string();
+        let msg = json!({ "command": command, "request_id": request_id });
+
+        //eprintln!("send to {}: {}", self.name, msg);
+        let stream = self.stream.get_mut();
+        stream
+            .set_write_timeout(Some(self.timeout))
+            .map_err(MpvError::Write)?;
+        writeln!(stream, "{msg}").map_err(MpvError::Write)?;
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let Some(parsed) = self.read_message(deadline)? else {
+                return Err(MpvError::Timeout {
+                    command: name,
+                    after: self.timeout,
+                });
+            };
+
+            // Event lines and replies to other requests are not ours
+            if parsed.get("request_id").and_then(|v| v.as_u64()) != Some(request_id) {
+                continue;
+            }
+
+            return match parsed.get("error").and_then(|v| v.as_str()) {
+                Some("success") => Ok(parsed),
+                Some(error) => Err(MpvError::Mpv {
+                    command: name,
+                    error: error.to_string(),
+                }),
+                None => Err(MpvError::MalformedJson(format!(
+                    "reply without error field: {parsed}"
+                ))),
+            };
+        }
+    }
+
+    pub fn get_property<V: DeserializeOwned>(&mut self, name: &str) -> Result<V, MpvError> {
+        let mut response = self.command(json!(["get_property", name]))?;
+        let data = response
+            .get_mut("data")
+            .map(serde_json::Value::take)
+            .unwrap_or_default();
+
+        serde_json::from_value(data).map_err(|e| MpvError::MalformedJson(format!("{name}: {e}")))
+    }
+
+    pub fn set_property<V: Serialize>(&mut self, name: &str, value: V) -> Result<(), MpvError> {
+        self.command(json!(["set_property", name, value]))?;
+        Ok(())
+    }
+
+    /// Subscribes to changes of a property. The latest value is available
+    /// through [`MpvClient::get_observed`] and every change is queued as a
+    /// [`MpvEvent::PropertyChange`]. Returns the observer id.
+    pub fn observe(&mut self, name: &str) -> Result<u64, MpvError> {
+        let id = self.next_observe_id;
+        self.next_observe_id += 1;
+
+        self.command(json!(["observe_property", id, name]))?;
+        Ok(id)
+    }
+
+    /// Latest value of an observed property, `None` while mpv has none.
+    pub fn get_observed<V: DeserializeOwned>(&self, name: &str) -> Option<V> {
+        self.observed
+            .get(name)
+            .and_then(|value| V::deserialize(value).ok())
+    }
+
+    /// Returns the next queued event, waiting at most `timeout` for one to
+    /// arrive. `Ok(None)` means the timeout expired.
+    pub fn next_event(&mut self, timeout: Duration) -> Result<Option<MpvEvent>, MpvError> {
+        let deadline = Instant::now() + timeout;
+        while self.events.is_empty() {
+            // Stray replies are dropped, nobody is waiting for them any more
+            if self.read_message(deadline)?.is_none() {
+                return Ok(None);
+            }
+        }
+
+        Ok(self.events.pop_front())
+    }
+
+    /// Reads one JSON line from mpv, giving up at `deadline`. Event lines are
+    /// also queued for [`MpvClient::next_event`]. `Ok(None)` means the
+    /// deadline passed.
+    fn read_message(&mut self, deadline: Instant) -> Result<Option<serde_json::Value>, MpvError> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            self.stream
+                .get_mut()
+                .set_read_timeout(Some(remaining))
+                .map_err(MpvError::Read)?;
 
-pub fn wait_for_the_end(socket_path: &str) {
-    eprintln!("Wait until 30 seconds before end of the video ...");
-    loop {
-        if let Some(playback_time) = get_playback_time(&socket_path) {
-            if let Some(duration) = get_duration(&socket_path) {
-                let video_path = get_video_path(socket_path).unwrap_or(String::from("unknown"));
-                let percent = playback_time * 100. / duration;
-                eprintln!(
-                    "instance{socket_path}: {video_path}: {playback_time:.0} / {duration:.0} ({percent:.0}%)"
-                );
-
-                if duration - playback_time <= 30.0 {
-                    break;
+            // A partial line survives a read timeout and is completed next time
+            let n = match self.stream.read_until(b'\n', &mut self.line) {
+                Ok(n) => n,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Ok(None);
                 }
-            } else {
-                break;
+                Err(e) => return Err(MpvError::Read(e)),
+            };
+            if n == 0 && self.line.is_empty() {
+                return Err(MpvError::Read(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection to mpv closed",
+                )));
             }
-        } else {
-            break;
+            if self.line.last() != Some(&b'\n') && n != 0 {
+                continue;
+            }
+
+            //eprintln!("response from mpv: {}", String::from_utf8_lossy(&self.line));
+            let parsed = serde_json::from_slice::<serde_json::Value>(&self.line)
+                .map_err(|e| MpvError::MalformedJson(e.to_string()));
+            self.line.clear();
+            let parsed = parsed?;
+
+            if let Some(event) = MpvEvent::from_json(&parsed) {
+                self.queue_event(event);
+            }
+
+            return Ok(Some(parsed));
         }
+    }
+
+    fn queue_event(&mut self, event: MpvEvent) {
+        if let MpvEvent::PropertyChange { name, data } = &event {
+            self.observed.insert(name.clone(), data.clone());
 
-        thread::sleep(Duration::from_millis(500));
+            // Only the latest value of a property is of interest
+            self.events.retain(
+                |queued| !matches!(queued, MpvEvent::PropertyChange { name: n, .. } if n == name),
+            );
+        }
+
+        self.events.push_back(event);
     }
-}
 
-pub fn start_video(socket_path: &str, path: &Path, volume: u8) -> Result<f64, String> {
-    let msg = json!({ "command": ["loadfile", path, "replace"], });
-    send_msg(&socket_path, msg)?;
-
-    // Wait until volume can be set successful
-    eprintln!("Wait until volume is set successfully");
-    let mut counter = 0;
-    loop {
-        // First commands are to ensure that they are working
-        if let Some(_) = get_playback_time(&socket_path) {
-            if let Some(_) = get_video_path(&socket_path) {
-                if let Some(duration) = get_duration(&socket_path) {
-                    if set_volume(&socket_path, volume).is_ok() {
-                        return Ok(duration);
+    /// Moves the window to `screen` in fullscreen mode.
+    pub fn full_screen(&mut self, screen: usize) -> Result<(), MpvError> {
+        self.set_property("fullscreen", false)?;
+        self.set_fs_screen(screen)?;
+        self.set_property("fullscreen", true)?;
+
+        Ok(())
+    }
+
+    pub fn quit(&mut self) -> Result<(), MpvError> {
+        let result = self.command(json!(["quit"]))?;
+        eprintln!("{result:#}");
+
+        Ok(())
+    }
+
+    pub fn get_video_path(&mut self) -> Result<String, MpvError> {
+        self.get_property("path")
+    }
+
+    pub fn get_playback_time(&mut self) -> Result<f64, MpvError> {
+        self.get_property("time-pos")
+    }
+
+    /// Seeks to an absolute position in seconds.
+    pub fn set_playback_time(&mut self, position: f64) -> Result<(), MpvError> {
+        self.set_property("time-pos", position)
+    }
+
+    pub fn get_duration(&mut self) -> Result<f64, MpvError> {
+        self.get_property("duration")
+    }
+
+    /// Blocks until `seconds_left` seconds remain before `cue_out`, or before
+    /// the end of the current file without one, or until the file ends.
+    /// Driven by the observed `time-pos`/`duration` and the `end-file`/`idle`
+    /// events. Gives up within a second once `interrupt` is set.
+    ///
+    /// A playing mpv reports a new `time-pos` many times a second. Once it
+    /// has not for the command timeout, mpv is asked whether it is paused: no
+    /// reply, or an unpaused player that does not move on, counts as
+    /// [`TrackEnd::Disconnected`].
+    pub fn wait_for_the_end(
+        &mut self,
+        seconds_left: f64,
+        cue_out: Option<f64>,
+        interrupt: &AtomicBool,
+    ) -> TrackEnd {
+        match cue_out {
+            Some(cue_out) => {
+                eprintln!("Wait until {seconds_left} seconds before cue-out at {cue_out} ...")
+            }
+            None => eprintln!("Wait until {seconds_left} seconds before end of the video ..."),
+        }
+        let video_path = self
+            .get_video_path()
+            .unwrap_or_else(|_| String::from("unknown"));
+        let mut last_logged = None;
+        let mut last_time_pos = None;
+        let mut last_progress = Instant::now();
+        loop {
+            if interrupt.load(Ordering::SeqCst) {
+                return TrackEnd::Interrupted;
+            }
+
+            let time_pos = self.get_observed::<f64>("time-pos");
+            if time_pos != last_time_pos {
+                last_time_pos = time_pos;
+                last_progress = Instant::now();
+            }
+
+            if let (Some(playback_time), Some(duration)) = (
+                self.get_observed::<f64>("time-pos"),
+                self.get_observed::<f64>("duration"),
+            ) {
+                // Log progress every ten seconds of playback
+                let bucket = (playback_time / 10.).floor() as i64;
+                if last_logged != Some(bucket) {
+                    last_logged = Some(bucket);
+                    let percent = playback_time * 100. / duration;
+                    eprintln!(
+                        "instance{}: {video_path}: {playback_time:.0} / {duration:.0} ({percent:.0}%)",
+                        self.name
+                    );
+                }
+
+                let end = cue_out.map_or(duration, |cue_out| cue_out.min(duration));
+                if end - playback_time <= seconds_left {
+                    return TrackEnd::TimeToMix;
+                }
+            }
+
+            match self.next_event(Duration::from_secs(1)) {
+                Ok(Some(MpvEvent::EndFile { reason })) => {
+                    eprintln!("instance{}: file ended ({reason})", self.name);
+                    return TrackEnd::FileEnded;
+                }
+                Ok(Some(MpvEvent::Idle)) => {
+                    eprintln!("instance{}: mpv is idle", self.name);
+                    return TrackEnd::FileEnded;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("instance{}: {e}", self.name);
+                    return TrackEnd::Disconnected;
+                }
+            }
+
+            if last_progress.elapsed() >= self.timeout {
+                match self.get_property::<bool>("pause") {
+                    Ok(true) => last_progress = Instant::now(),
+                    Ok(false) => {
+                        eprintln!(
+                            "instance{}: playback is stuck for {:?}",
+                            self.name,
+                            last_progress.elapsed()
+                        );
+                        return TrackEnd::Disconnected;
+                    }
+                    Err(e) => {
+                        eprintln!("instance{}: {e}", self.name);
+                        return TrackEnd::Disconnected;
                     }
                 }
             }
         }
-        thread::sleep(Duration::from_millis(200));
+    }
+
+    /// Waits for the `end-file` event of the current file. Returns `false` if
+    /// it did not arrive within `timeout`.
+    pub fn wait_for_end_file(&mut self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.next_event(remaining) {
+                Ok(Some(MpvEvent::EndFile { .. } | MpvEvent::Idle)) => return true,
+                Ok(Some(_)) => {}
+                Ok(None) => return false,
+                // mpv is gone, so the file is over as well
+                Err(_) => return true,
+            }
+        }
+    }
 
-        if counter == 100 {
-            return Err(format!("Cannot set volume after {counter} tryies"));
+    /// Loads the file, skips to `cue_in` if there is one and sets the volume.
+    /// Returns the duration of the file.
+    pub fn start_video(
+        &mut self,
+        path: &Path,
+        volume: u8,
+        cue_in: Option<f64>,
+    ) -> Result<f64, MpvError> {
+        let duration = self.load_video(path)?;
+        if let Some(cue_in) = cue_in {
+            self.set_playback_time(cue_in)?;
         }
+        self.set_volume(volume.into())?;
 
-        counter += 1;
+        Ok(duration)
+    }
+
+    /// Replaces the current file and waits until mpv loaded it. Playback
+    /// state such as `pause` and `volume` carries over from the previous
+    /// file. Returns the duration of the new file.
+    pub fn load_video(&mut self, path: &Path) -> Result<f64, MpvError> {
+        self.events.clear();
+        self.observed.clear();
+        self.command(json!(["loadfile", path, "replace"]))?;
+
+        eprintln!("Wait until file is loaded");
+        let deadline = Instant::now() + self.load_timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.next_event(remaining)? {
+                Some(MpvEvent::FileLoaded) => break,
+                Some(MpvEvent::EndFile { reason }) if reason == "error" => {
+                    return Err(MpvError::Mpv {
+                        command: format!("loadfile {}", path.display()),
+                        error: reason,
+                    });
+                }
+                Some(_) => {}
+                None => {
+                    return Err(MpvError::Timeout {
+                        command: format!("loadfile {}", path.display()),
+                        after: self.load_timeout,
+                    });
+                }
+            }
+        }
+
+        self.get_duration()
     }
 }
 
-pub fn send_msg(socket_path: &str, msg: serde_json::Value) -> Result<serde_json::Value, String> {
-    let mut stream = UnixStream::connect(socket_path)
-        .map_err(|e| format!("Failed to connect to UNIX socket: {e}"))?;
-    let reader = BufReader::new(
-        stream
-            .try_clone()
-            .map_err(|e| format!("Failed to create buffer reader: {e}"))?,
-    );
-
-    //eprintln!("send to {}: {}", socket_path, msg.to_string());
-    writeln!(stream, "{}", msg.to_string())
-        .map_err(|e| format!("Cannot write to UNIX socket: {e}"))?;
-
-    for line in reader.lines() {
-        if let Ok(text) = line {
-            //eprintln!("response from mpv: {text}");
-            let result: Result<serde_json::Value, _> = serde_json::from_str(&text);
-            if let Ok(parsed) = result {
-                if let Some(value) = parsed.get("error") {
-                    if value == "success" {
-                        return Ok(parsed);
-                    } else {
-                        return Err(value.to_string());
-                    }
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::thread;
+
+    use super::*;
+    use crate::fake_mpv::{FakeMpv, FakeMpvOptions};
+    use crate::transport::MemoryTransport;
+
+    /// A hand-written mpv on the other end of a [`MemoryTransport`]: the
+    /// observers of [`MpvClient::new`] are accepted, every other request is
+    /// answered with the lines `script` returns for its request id.
+    fn scripted(
+        script: impl Fn(u64) -> Vec<serde_json::Value> + Send + 'static,
+    ) -> MemoryTransport {
+        let (client, server) = MemoryTransport::pair();
+
+        thread::spawn(move || {
+            let mut server = BufReader::new(server);
+            let mut line = Vec::new();
+            while server.read_until(b'\n', &mut line).unwrap_or(0) > 0 {
+                let request: serde_json::Value = serde_json::from_slice(&line).unwrap();
+                line.clear();
+                let request_id = request["request_id"].as_u64().unwrap();
+
+                let replies = if request["command"][0] == "observe_property" {
+                    vec![json!({ "request_id": request_id, "error": "success" })]
+                } else {
+                    script(request_id)
+                };
+                for reply in replies {
+                    writeln!(server.get_mut(), "{reply}").unwrap();
                 }
+            }
+        });
+
+        client
+    }
+
+    #[test]
+    fn replies_are_matched_by_request_id() {
+        let transport = scripted(|request_id| {
+            vec![
+                json!({ "event": "file-loaded" }),
+                json!({ "request_id": request_id + 100, "error": "success", "data": 1 }),
+                json!({ "event": "property-change", "id": 1, "name": "time-pos", "data": 12.5 }),
+                json!({ "request_id": request_id, "error": "success", "data": 7 }),
+            ]
+        });
+        let mut client = MpvClient::new(transport, "memory").unwrap();
 
-                return Err("Unexpected structure of JSON returned by mpv".into());
+        let volume: f64 = client.get_property("volume").unwrap();
+        assert_eq!(volume, 7.);
+
+        // The events in between were queued, the stray reply was not
+        assert_eq!(
+            client.next_event(Duration::ZERO).unwrap(),
+            Some(MpvEvent::FileLoaded)
+        );
+        assert!(matches!(
+            client.next_event(Duration::ZERO).unwrap(),
+            Some(MpvEvent::PropertyChange { name, .. }) if name == "time-pos"
+        ));
+        assert_eq!(client.next_event(Duration::ZERO).unwrap(), None);
+        assert_eq!(client.get_observed::<f64>("time-pos"), Some(12.5));
+    }
+
+    #[test]
+    fn unanswered_command_times_out() {
+        let transport = scripted(|_| vec![json!({ "event": "idle" })]);
+        let mut client = MpvClient::new(transport, "memory").unwrap();
+        client.set_timeout(Duration::from_millis(50));
+
+        let result = client.get_property::<f64>("volume");
+        assert!(matches!(result, Err(MpvError::Timeout { .. })));
+        assert_eq!(
+            client.next_event(Duration::ZERO).unwrap(),
+            Some(MpvEvent::Idle)
+        );
+    }
+
+    #[test]
+    fn hung_mpv_ends_the_wait() {
+        // Answers the first request with some progress, then nothing at all
+        let answered = AtomicBool::new(false);
+        let transport = scripted(move |request_id| {
+            if answered.swap(true, Ordering::SeqCst) {
+                return vec![];
             }
-        }
+            vec![
+                json!({ "event": "property-change", "id": 1, "name": "time-pos", "data": 10. }),
+                json!({ "event": "property-change", "id": 2, "name": "duration", "data": 180. }),
+                json!({ "request_id": request_id, "error": "success", "data": "a.mp4" }),
+            ]
+        });
+        let mut client = MpvClient::new(transport, "memory").unwrap();
+        client.set_timeout(Duration::from_millis(50));
+
+        let end = client.wait_for_the_end(30., None, &AtomicBool::new(false));
+        assert_eq!(end, TrackEnd::Disconnected);
+    }
+
+    #[test]
+    fn stalled_playback_ends_the_wait() {
+        // Answers every request, but the position never moves
+        let transport = scripted(|request_id| {
+            vec![
+                json!({ "event": "property-change", "id": 1, "name": "time-pos", "data": 10. }),
+                json!({ "event": "property-change", "id": 2, "name": "duration", "data": 180. }),
+                json!({ "request_id": request_id, "error": "success", "data": false }),
+            ]
+        });
+        let mut client = MpvClient::new(transport, "memory").unwrap();
+        client.set_timeout(Duration::from_millis(50));
+
+        let end = client.wait_for_the_end(30., None, &AtomicBool::new(false));
+        assert_eq!(end, TrackEnd::Disconnected);
     }
 
-    Err("Response from mpv is not valid JSON".into())
+    #[test]
+    fn talks_to_fake_mpv_in_memory() {
+        let fake = FakeMpv::in_memory(FakeMpvOptions {
+            speed: 100.,
+            ..Default::default()
+        });
+        let mut client = MpvClient::new(fake.connect(), "memory").unwrap();
+
+        // Loading interleaves events and property changes with the replies
+        let duration = client.load_video(Path::new("a.mp4")).unwrap();
+        assert_eq!(duration, 180.);
+        client.set_volume(42.).unwrap();
+        assert_eq!(client.get_volume().unwrap(), 42.);
+        assert_eq!(client.get_video_path().unwrap(), "a.mp4");
+
+        client.set_timeout(Duration::from_millis(200));
+        let result = client.get_property::<f64>("no-such-property");
+        assert!(
+            matches!(result, Err(MpvError::Mpv { error, .. }) if error == "property not found")
+        );
+    }
 }
@@ -0,0 +1,50 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::deck::IpcConfig;
+use crate::fade::CrossfadeConfig;
+use crate::loudness::LoudnessConfig;
+use crate::media_files::SelectionConfig;
+use crate::tempo::TempoConfig;
+
+/// Settings read from `config.json`. Every field is optional, a missing file
+/// means the defaults.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    /// Where `media-files.csv`, `categories.csv` and the files kept next to
+    /// them are read and written.
+    pub data_dir: PathBuf,
+    pub ipc: IpcConfig,
+    pub crossfade: CrossfadeConfig,
+    pub loudness: LoudnessConfig,
+    pub tempo: TempoConfig,
+    pub selection: SelectionConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from("."),
+            ipc: IpcConfig::default(),
+            crossfade: CrossfadeConfig::default(),
+            loudness: LoudnessConfig::default(),
+            tempo: TempoConfig::default(),
+            selection: SelectionConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+
+        let content = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&content)?)
+    }
+}
@@ -0,0 +1,572 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::commands::{DEFAULT_LOAD_TIMEOUT, DEFAULT_TIMEOUT, MpvClient};
+use crate::error::MpvError;
+use crate::fake_mpv::{FakeMpv, FakeMpvOptions};
+use crate::media_files::MediaFile;
+
+/// mpv's default `volume-max`, the limit for boosting quiet tracks.
+const MAX_VOLUME: f64 = 130.;
+
+/// How the decks are reached.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct IpcConfig {
+    /// Where the IPC socket of each deck is created, as `mpv{index}.socket`.
+    pub socket_dir: PathBuf,
+    /// Decks that play on another machine, by index, e.g.
+    /// `{"1": "10.0.0.5:9000"}`. The mpv there has its IPC socket bridged to
+    /// TCP and is neither started nor stopped by the DJ.
+    pub remote: HashMap<usize, String>,
+    /// Seconds a deck has to answer a command.
+    pub timeout: f64,
+    /// Seconds a deck may take to load a file.
+    pub load_timeout: f64,
+    /// Seconds a freshly started deck may take to open its IPC socket.
+    pub socket_timeout: f64,
+}
+
+impl Default for IpcConfig {
+    fn default() -> Self {
+        Self {
+            socket_dir: PathBuf::from("/tmp"),
+            remote: HashMap::new(),
+            timeout: DEFAULT_TIMEOUT.as_secs_f64(),
+            load_timeout: DEFAULT_LOAD_TIMEOUT.as_secs_f64(),
+            socket_timeout: 10.,
+        }
+    }
+}
+
+/// A configured number of seconds, anything below zero being zero.
+fn seconds(value: f64) -> Duration {
+    Duration::from_secs_f64(value.max(0.))
+}
+
+/// What answers on the IPC socket of each deck.
+#[derive(Debug, Clone)]
+pub enum Backend {
+    /// A real `mpv` process per deck.
+    Mpv,
+    /// An in-process [`FakeMpv`], for headless runs without screens.
+    Fake(FakeMpvOptions),
+    /// Players that already listen on the sockets, e.g. started by hand or
+    /// by a test. Nothing is spawned or killed, a failed deck is only
+    /// connected again.
+    Attach,
+}
+
+enum DeckProcess {
+    Mpv(Child),
+    Fake(FakeMpv),
+    Attached,
+    Exited,
+}
+
+impl DeckProcess {
+    fn spawn(backend: &Backend, index: usize, socket_path: &str) -> io::Result<Self> {
+        if matches!(backend, Backend::Attach) {
+            return Ok(DeckProcess::Attached);
+        }
+        let _ = std::fs::remove_file(socket_path);
+
+        match backend {
+            Backend::Mpv => {
+                let mut cmd = Command::new("mpv");
+                let cmd = cmd
+                    .arg("--idle")
+                    .arg("--force-window")
+                    .arg("--no-terminal")
+                    .arg("--quiet")
+                    .arg("--fs")
+                    .arg(format!("--fs-screen={}", index + 1))
+                    .arg(format!("--input-ipc-server={}", socket_path));
+
+                eprintln!("{cmd:#?}");
+                Ok(DeckProcess::Mpv(cmd.spawn()?))
+            }
+            Backend::Fake(options) => Ok(DeckProcess::Fake(FakeMpv::spawn(
+                socket_path,
+                options.clone(),
+            )?)),
+            Backend::Attach => Ok(DeckProcess::Attached),
+        }
+    }
+
+    /// Why the process is not usable any more, `None` while it runs.
+    fn exit_reason(&mut self) -> Option<String> {
+        match self {
+            DeckProcess::Mpv(child) => match child.try_wait() {
+                Ok(None) => None,
+                Ok(Some(status)) => Some(format!("mpv exited: {status}")),
+                Err(e) => Some(format!("mpv process cannot be checked: {e}")),
+            },
+            DeckProcess::Fake(fake) if !fake.is_running() => Some("fake mpv stopped".into()),
+            DeckProcess::Fake(_) | DeckProcess::Attached => None,
+            DeckProcess::Exited => Some("process was killed".into()),
+        }
+    }
+
+    /// Terminates the process and reaps it, so no zombie is left behind.
+    fn kill(&mut self) {
+        self.terminate(Duration::ZERO);
+    }
+
+    /// Gives the process `grace` to exit on its own, e.g. after `quit`, then
+    /// kills and reaps it.
+    fn terminate(&mut self, grace: Duration) {
+        match std::mem::replace(self, DeckProcess::Exited) {
+            DeckProcess::Mpv(mut child) => {
+                let deadline = Instant::now() + grace;
+                while let Ok(None) = child.try_wait() {
+                    if Instant::now() >= deadline {
+                        let _ = child.kill();
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(50));
+                }
+                match child.wait() {
+                    Ok(status) => eprintln!("mpv process {} exited: {status}", child.id()),
+                    Err(e) => eprintln!("mpv process {} could not be reaped: {e}", child.id()),
+                }
+            }
+            DeckProcess::Fake(mut fake) => fake.stop(),
+            // Someone else owns it, it stays as it is
+            DeckProcess::Attached => *self = DeckProcess::Attached,
+            DeckProcess::Exited => {}
+        }
+    }
+}
+
+/// What [`DeckPool::supervise`] did about a failed deck.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recovery {
+    /// The deck was idle or only preloaded and is ready again.
+    Restarted,
+    /// The track on air continues at the given position.
+    Resumed(f64),
+    /// The track on air could not be resumed, the deck is idle now.
+    Lost,
+    /// No new process could be started, the next check tries again.
+    Failed(String),
+}
+
+/// A deck that died, and how it was brought back.
+#[derive(Debug, Clone)]
+pub struct Incident {
+    pub deck: usize,
+    pub reason: String,
+    pub recovery: Recovery,
+}
+
+/// One long-lived mpv instance that plays track after track.
+pub struct Deck {
+    pub index: usize,
+    pub client: MpvClient,
+    /// The file loaded on this deck, if any.
+    pub media_file: Option<MediaFile>,
+    /// Duration of the loaded file in seconds.
+    pub duration: f64,
+    /// Amplitude gain that levels the loaded file, applied under every
+    /// volume set with [`Deck::set_level`].
+    pub gain: f64,
+    /// Whether the loaded file is on air or only preloaded.
+    pub playing: bool,
+    backend: Backend,
+    ipc: IpcConfig,
+    socket_path: String,
+    process: DeckProcess,
+}
+
+impl Deck {
+    /// Starts the player of deck `index` and connects to it, or only
+    /// connects if the deck is remote.
+    pub fn spawn(backend: &Backend, index: usize, ipc: &IpcConfig) -> Result<Self, MpvError> {
+        if let Some(address) = ipc.remote.get(&index) {
+            eprintln!("Deck {index} plays on {address}");
+            let client = MpvClient::connect_tcp(address.as_str())?;
+            return Self::attach(backend, index, ipc, client, address, DeckProcess::Attached);
+        }
+
+        let socket_path = ipc
+            .socket_dir
+            .join(format!("mpv{index}.socket"))
+            .to_string_lossy()
+            .into_owned();
+        let process =
+            DeckProcess::spawn(backend, index, &socket_path).map_err(MpvError::Connect)?;
+
+        let socket_timeout = seconds(ipc.socket_timeout);
+        let deadline = Instant::now() + socket_timeout;
+        while !Path::new(&socket_path).exists() {
+            if Instant::now() >= deadline {
+                return Err(MpvError::Timeout {
+                    command: format!("start of deck {index}"),
+                    after: socket_timeout,
+                });
+            }
+            eprintln!("Cannot see IPC socket yet, waiting ...");
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let client = MpvClient::connect(&socket_path)?;
+        Self::attach(backend, index, ipc, client, &socket_path, process)
+    }
+
+    fn attach(
+        backend: &Backend,
+        index: usize,
+        ipc: &IpcConfig,
+        mut client: MpvClient,
+        socket_path: &str,
+        process: DeckProcess,
+    ) -> Result<Self, MpvError> {
+        client.set_timeout(seconds(ipc.timeout));
+        client.set_load_timeout(seconds(ipc.load_timeout));
+        // Remembered so a crashed deck can come back at the same level
+        client.observe("volume")?;
+
+        Ok(Self {
+            index,
+            client,
+            media_file: None,
+            duration: 0.,
+            gain: 1.,
+            playing: false,
+            backend: backend.clone(),
+            ipc: ipc.clone(),
+            socket_path: socket_path.to_string(),
+            process,
+        })
+    }
+
+    /// Why this deck is not usable any more, `None` if it is healthy. A deck
+    /// is dead when its process exited or its socket stopped answering.
+    pub fn failure(&mut self) -> Option<String> {
+        if let Some(reason) = self.process.exit_reason() {
+            return Some(reason);
+        }
+
+        match self.client.get_volume() {
+            Err(e) if e.is_fatal() || matches!(e, MpvError::Timeout { .. }) => {
+                Some(format!("IPC socket does not answer: {e}"))
+            }
+            _ => None,
+        }
+    }
+
+    /// Replaces the process with a new one of the same configuration and
+    /// restores what the deck was doing: a preloaded file is loaded again,
+    /// the file on air continues at its last known position.
+    pub fn respawn(&mut self) -> Recovery {
+        let time_pos = self.client.get_observed::<f64>("time-pos");
+        let volume = self.client.get_observed::<f64>("volume").unwrap_or(100.);
+        let media_file = self.media_file.take();
+        let playing = self.playing;
+        let gain = self.gain;
+
+        // The old process has to be gone before the socket path is reused
+        self.process.kill();
+        let fresh = match Deck::spawn(&self.backend, self.index, &self.ipc) {
+            Ok(fresh) => fresh,
+            Err(e) => {
+                self.media_file = media_file;
+                return Recovery::Failed(e.to_string());
+            }
+        };
+        *self = fresh;
+        self.gain = gain;
+
+        let Some(media_file) = media_file else {
+            return Recovery::Restarted;
+        };
+
+        if !playing {
+            return match self.preload(&media_file) {
+                Ok(_) => Recovery::Restarted,
+                Err(e) => {
+                    eprintln!("deck {}: preload after restart failed: {e}", self.index);
+                    Recovery::Lost
+                }
+            };
+        }
+
+        let position = time_pos.unwrap_or(0.);
+        let resumed = self
+            .preload(&media_file)
+            .and_then(|_| self.client.set_playback_time(position))
+            .and_then(|_| self.client.set_volume(volume))
+            .and_then(|_| self.client.set_pause(false));
+        match resumed {
+            Ok(_) => {
+                self.playing = true;
+                Recovery::Resumed(position)
+            }
+            Err(e) => {
+                eprintln!("deck {}: resume after restart failed: {e}", self.index);
+                self.media_file = None;
+                self.playing = false;
+                Recovery::Lost
+            }
+        }
+    }
+
+    /// Loads the file paused and silent at its cue-in, so it is ready to be
+    /// faded in. Returns its duration.
+    pub fn preload(&mut self, media_file: &MediaFile) -> Result<f64, MpvError> {
+        eprintln!("Preload deck {}: {}", self.index, media_file.path.display());
+        self.client.set_pause(true)?;
+        // mpv keeps the speed of the previous file, e.g. after a nudge
+        self.client.set_speed(1.)?;
+        self.playing = false;
+        let duration = self
+            .client
+            .start_video(&media_file.path, 0, media_file.cue_in)?;
+        self.media_file = Some(media_file.clone());
+        self.duration = duration;
+
+        Ok(duration)
+    }
+
+    /// Starts playing the preloaded file at the given level.
+    pub fn play(&mut self, level: f64) -> Result<(), MpvError> {
+        self.set_level(level)?;
+        self.client.set_pause(false)?;
+        self.playing = true;
+
+        Ok(())
+    }
+
+    /// Levels the loaded file by `gain`. mpv cannot boost beyond
+    /// [`MAX_VOLUME`], a quiet track that needs more plays quieter.
+    pub fn set_gain(&mut self, gain: f64) {
+        self.gain = gain;
+
+        let volume = 100. * gain.cbrt();
+        if volume > MAX_VOLUME {
+            eprintln!(
+                "deck {}: gain needs volume {volume:.0}, limited to {MAX_VOLUME:.0}",
+                self.index
+            );
+        }
+    }
+
+    /// Sets the volume relative to the levelled track: 100 is the track at
+    /// its [`Deck::gain`], 0 is silence.
+    pub fn set_level(&mut self, level: f64) -> Result<(), MpvError> {
+        let volume = (level * self.gain.cbrt()).min(MAX_VOLUME);
+        self.client.set_volume(volume.trunc())
+    }
+
+    /// Stops playback, the deck goes idle until the next preload.
+    pub fn stop(&mut self) -> Result<(), MpvError> {
+        self.playing = false;
+        self.media_file = None;
+        self.duration = 0.;
+        self.gain = 1.;
+        self.client.command(json!(["stop"]))?;
+
+        Ok(())
+    }
+
+    /// Asks mpv to quit, reaps the process and removes its socket.
+    pub fn shutdown(mut self) {
+        let _ = self.client.quit();
+        if matches!(self.process, DeckProcess::Attached) {
+            return;
+        }
+        self.process.terminate(Duration::from_secs(2));
+        let _ = std::fs::remove_file(&self.socket_path);
+    }
+}
+
+impl Drop for Deck {
+    fn drop(&mut self) {
+        self.process.kill();
+    }
+}
+
+/// A fixed set of decks, started once and reused for every track.
+pub struct DeckPool {
+    decks: Vec<Deck>,
+}
+
+impl DeckPool {
+    pub fn start(backend: &Backend, count: usize, ipc: &IpcConfig) -> Result<Self, MpvError> {
+        let decks = (0..count)
+            .map(|index| Deck::spawn(backend, index, ipc))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { decks })
+    }
+
+    pub fn len(&self) -> usize {
+        self.decks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.decks.is_empty()
+    }
+
+    pub fn deck(&mut self, index: usize) -> &mut Deck {
+        &mut self.decks[index]
+    }
+
+    /// Mutable access to two different decks at once, e.g. for a crossfade.
+    pub fn pair(&mut self, a: usize, b: usize) -> (&mut Deck, &mut Deck) {
+        assert!(a != b, "a crossfade needs two different decks");
+        if a < b {
+            let (left, right) = self.decks.split_at_mut(b);
+            (&mut left[a], &mut right[0])
+        } else {
+            let (left, right) = self.decks.split_at_mut(a);
+            (&mut right[0], &mut left[b])
+        }
+    }
+
+    /// Checks every deck and respawns the dead ones. Each incident is logged
+    /// and returned, so the caller can react to a lost track.
+    pub fn supervise(&mut self) -> Vec<Incident> {
+        let mut incidents = Vec::new();
+
+        for deck in self.decks.iter_mut() {
+            let Some(reason) = deck.failure() else {
+                continue;
+            };
+
+            eprintln!("deck {} failed: {reason}, respawning it", deck.index);
+            let recovery = deck.respawn();
+            match &recovery {
+                Recovery::Restarted => eprintln!("deck {} is back", deck.index),
+                Recovery::Resumed(position) => {
+                    eprintln!("deck {} is back, resumed at {position:.0}s", deck.index)
+                }
+                Recovery::Lost => eprintln!("deck {} is back, but its track is lost", deck.index),
+                Recovery::Failed(e) => eprintln!("deck {} could not be restarted: {e}", deck.index),
+            }
+
+            incidents.push(Incident {
+                deck: deck.index,
+                reason,
+                recovery,
+            });
+        }
+
+        incidents
+    }
+
+    pub fn decks_mut(&mut self) -> impl Iterator<Item = &mut Deck> {
+        self.decks.iter_mut()
+    }
+
+    /// Quits every deck and waits for all of them to exit.
+    pub fn shutdown(self) {
+        for deck in self.decks {
+            deck.shutdown();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::fake_mpv::FakeMpvOptions;
+
+    #[test]
+    fn remote_deck_is_reached_over_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let fake = FakeMpv::in_memory(FakeMpvOptions::default());
+
+        let ipc = IpcConfig {
+            remote: HashMap::from([(0, address)]),
+            ..Default::default()
+        };
+        // No mpv is started for a remote deck, whatever the backend
+        let accepted = thread::spawn(move || listener.accept().unwrap().0);
+        let mut deck = {
+            let spawned = thread::spawn(move || Deck::spawn(&Backend::Mpv, 0, &ipc));
+            fake.serve(accepted.join().unwrap());
+            spawned.join().unwrap().unwrap()
+        };
+
+        let media_file = serde_json::from_value(json!({
+            "path": "a.mp4",
+            "category": "pop",
+            "played": 0,
+        }))
+        .unwrap();
+        assert_eq!(deck.preload(&media_file).unwrap(), 180.);
+        assert_eq!(fake.loaded_files(), ["a.mp4"]);
+        assert!(deck.failure().is_none());
+    }
+
+    #[test]
+    fn deck_that_never_opens_its_socket_times_out() {
+        let ipc = IpcConfig {
+            socket_dir: std::env::temp_dir().join(format!("mpv-dj-nothing-{}", std::process::id())),
+            socket_timeout: 0.3,
+            ..Default::default()
+        };
+
+        let started = Instant::now();
+        let result = Deck::spawn(&Backend::Attach, 0, &ipc);
+
+        assert!(
+            matches!(result, Err(MpvError::Timeout { after, .. }) if after == Duration::from_millis(300))
+        );
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn level_is_scaled_by_the_gain_on_the_cubic_volume() {
+        let fake = FakeMpv::in_memory(FakeMpvOptions::default());
+        let client = MpvClient::new(Box::new(fake.connect()) as _, "deck 0").unwrap();
+        let mut deck = Deck::attach(
+            &Backend::Attach,
+            0,
+            &IpcConfig::default(),
+            client,
+            "",
+            DeckProcess::Attached,
+        )
+        .unwrap();
+
+        let mut volume = |level: f64, gain: f64| {
+            deck.set_gain(gain);
+            deck.set_level(level).unwrap();
+            fake.volume_log().last().unwrap().1
+        };
+
+        // -18 dB is an eighth of the amplitude, half the volume
+        assert_eq!(volume(100., 0.125), 50.);
+        assert_eq!(volume(100., 1.), 100.);
+        assert_eq!(volume(0., 0.125), 0.);
+        // +6 dB needs more than full volume, +18 dB more than mpv allows
+        assert_eq!(volume(100., 2.), 125.);
+        assert_eq!(volume(100., 8.), MAX_VOLUME);
+
+        // The amplitude mpv plays is that of the level times the gain
+        for level in [10f64, 40., 75., 100.] {
+            for gain in [0.1, 0.5, 1., 1.5] {
+                let amplitude = (level / 100.).powi(3) * gain;
+                assert_eq!(
+                    volume(level, gain),
+                    (100. * amplitude.cbrt()).trunc(),
+                    "level {level}, gain {gain}"
+                );
+            }
+        }
+    }
+}
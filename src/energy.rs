@@ -0,0 +1,168 @@
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::loudness::Loudness;
+
+/// Energy ratings run from calm to peak.
+pub const MIN_ENERGY: f64 = 1.;
+pub const MAX_ENERGY: f64 = 10.;
+/// Tempo and loudness that count as least and most energetic.
+const CALM_BPM: f64 = 70.;
+const PEAK_BPM: f64 = 170.;
+const CALM_LUFS: f64 = -20.;
+const PEAK_LUFS: f64 = -6.;
+
+/// Energy rating derived from tempo and integrated loudness, whichever of
+/// them is known. Faster and louder is more energetic.
+pub fn estimate(bpm: Option<f64>, loudness: Option<&Loudness>) -> Option<f64> {
+    let tempo = bpm.map(|bpm| ((bpm - CALM_BPM) / (PEAK_BPM - CALM_BPM)).clamp(0., 1.));
+    let loudness = loudness.map(|loudness| {
+        ((loudness.integrated - CALM_LUFS) / (PEAK_LUFS - CALM_LUFS)).clamp(0., 1.)
+    });
+
+    let share = match (tempo, loudness) {
+        (Some(tempo), Some(loudness)) => 0.6 * tempo + 0.4 * loudness,
+        (Some(share), None) | (None, Some(share)) => share,
+        (None, None) => return None,
+    };
+    let energy = MIN_ENERGY + share * (MAX_ENERGY - MIN_ENERGY);
+
+    Some((energy * 10.).round() / 10.)
+}
+
+/// Target energy at one point of the set.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct CurvePoint {
+    /// Position in the set, from 0 at its start to 1 at its planned end.
+    pub at: f64,
+    pub energy: f64,
+}
+
+/// The energy the set should have over time, e.g. a warm-up, a peak and a
+/// cool-down.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SetPlan {
+    pub enabled: bool,
+    /// Planned length of the set. After it, the last point of the curve
+    /// holds.
+    pub minutes: f64,
+    /// Points of the curve, in order; the energy in between is interpolated.
+    pub curve: Vec<CurvePoint>,
+    /// Difference in energy at which a track is a third as likely as one
+    /// right on the curve. Tracks without an energy rating count as this far
+    /// off.
+    pub tolerance: f64,
+}
+
+impl Default for SetPlan {
+    fn default() -> Self {
+        let point = |at, energy| CurvePoint { at, energy };
+        Self {
+            enabled: false,
+            minutes: 240.,
+            curve: vec![
+                point(0., 3.),
+                point(0.3, 6.),
+                point(0.75, 9.),
+                point(0.9, 8.),
+                point(1., 4.),
+            ],
+            tolerance: 1.5,
+        }
+    }
+}
+
+impl SetPlan {
+    /// The energy the plan asks for `minutes` into the set.
+    pub fn target(&self, minutes: f64) -> Option<f64> {
+        if !self.enabled || self.minutes <= 0. {
+            return None;
+        }
+        let at = minutes / self.minutes;
+
+        let first = self.curve.first()?;
+        if at <= first.at {
+            return Some(first.energy);
+        }
+        let energy = self
+            .curve
+            .windows(2)
+            .find(|pair| at < pair[1].at)
+            .map(|pair| {
+                let progress = (at - pair[0].at) / (pair[1].at - pair[0].at);
+                pair[0].energy + (pair[1].energy - pair[0].energy) * progress
+            })
+            .unwrap_or(self.curve.last()?.energy);
+
+        Some(energy)
+    }
+
+    /// How much more likely a track of `energy` is chosen than one that is
+    /// far off `target`, 1 right on the curve.
+    pub fn closeness(&self, target: f64, energy: Option<f64>) -> f64 {
+        let distance = energy.map_or(self.tolerance, |energy| (energy - target).abs());
+        if self.tolerance <= 0. {
+            return if distance == 0. { 1. } else { 0. };
+        }
+
+        (-(distance / self.tolerance).powi(2)).exp()
+    }
+}
+
+/// Follows the set while it plays: the plan's target whenever a track goes
+/// on air and how far the tracks were off it.
+pub struct SetProgress {
+    started: Instant,
+    tracks: u32,
+    total_deviation: f64,
+}
+
+impl SetProgress {
+    pub fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            tracks: 0,
+            total_deviation: 0.,
+        }
+    }
+
+    pub fn minutes(&self) -> f64 {
+        self.started.elapsed().as_secs_f64() / 60.
+    }
+
+    /// Remembers that a track of `energy` went on air while the plan aimed
+    /// at `target`.
+    pub fn record(&mut self, target: f64, energy: Option<f64>) {
+        let Some(energy) = energy else {
+            eprintln!(
+                "set plan: {:.0} minutes in, target energy {target:.1}, playing a track without energy rating",
+                self.minutes()
+            );
+            return;
+        };
+
+        self.tracks += 1;
+        self.total_deviation += (energy - target).abs();
+        eprintln!(
+            "set plan: {:.0} minutes in, target energy {target:.1}, playing {energy:.1}, {:.1} off on average",
+            self.minutes(),
+            self.total_deviation / f64::from(self.tracks)
+        );
+    }
+
+    /// Logs how well the set followed the plan.
+    pub fn report(&self) {
+        if self.tracks == 0 {
+            return;
+        }
+
+        eprintln!(
+            "set plan: {} rated tracks in {:.0} minutes were {:.1} off the curve on average",
+            self.tracks,
+            self.minutes(),
+            self.total_deviation / f64::from(self.tracks)
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+/// Everything that can go wrong while talking to mpv over IPC.
+#[derive(Debug)]
+pub enum MpvError {
+    /// The IPC connection could not be opened.
+    Connect(io::Error),
+    /// The command could not be written to the connection.
+    Write(io::Error),
+    /// Reading the reply failed, or mpv closed the connection.
+    Read(io::Error),
+    /// No reply arrived before the deadline.
+    Timeout { command: String, after: Duration },
+    /// mpv sent something that is not the JSON we expect.
+    MalformedJson(String),
+    /// mpv answered, but reported an error such as "property unavailable".
+    Mpv { command: String, error: String },
+}
+
+impl MpvError {
+    /// mpv is alive but the property has no value yet, e.g. while a file is
+    /// still loading.
+    pub fn is_property_unavailable(&self) -> bool {
+        matches!(self, MpvError::Mpv { error, .. } if error == "property unavailable")
+    }
+
+    /// The connection is unusable and mpv is most likely gone.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            MpvError::Connect(_) | MpvError::Write(_) | MpvError::Read(_)
+        )
+    }
+}
+
+impl fmt::Display for MpvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MpvError::Connect(e) => write!(f, "Failed to connect to mpv: {e}"),
+            MpvError::Write(e) => write!(f, "Cannot send to mpv: {e}"),
+            MpvError::Read(e) => write!(f, "Cannot read from mpv: {e}"),
+            MpvError::Timeout { command, after } => {
+                write!(f, "No reply from mpv to {command} within {after:?}")
+            }
+            MpvError::MalformedJson(msg) => write!(f, "Malformed JSON returned by mpv: {msg}"),
+            MpvError::Mpv { command, error } => write!(f, "mpv rejected {command}: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for MpvError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MpvError::Connect(e) | MpvError::Write(e) | MpvError::Read(e) => Some(e),
+            _ => None,
+        }
+    }
+}
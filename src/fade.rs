@@ -0,0 +1,204 @@
+use std::f64::consts::FRAC_PI_2;
+
+use serde::{Deserialize, Serialize};
+
+/// Shape of a crossfade. Each curve maps the progress of a fade, from 0 to 1,
+/// to the amplitude gain of the incoming deck; the outgoing deck follows the
+/// same curve backwards.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FadeCurve {
+    /// mpv's volume changes at a constant rate, the ramp of earlier versions.
+    #[default]
+    LinearVolume,
+    /// Gain changes at a constant rate, which dips in the middle of the mix.
+    Linear,
+    /// The summed power of both decks stays constant for uncorrelated music.
+    EqualPower,
+    /// Gain changes by the same number of decibels per second, from -60 dB.
+    Logarithmic,
+    /// Slow at both ends and fast in the middle.
+    SCurve,
+}
+
+/// How far below full scale the logarithmic curve starts.
+const LOG_FLOOR_DB: f64 = -60.;
+
+impl FadeCurve {
+    /// Amplitude gain of the incoming deck after `progress` of the fade.
+    pub fn fade_in(self, progress: f64) -> f64 {
+        let x = progress.clamp(0., 1.);
+        match self {
+            FadeCurve::LinearVolume => x.powi(3),
+            FadeCurve::Linear => x,
+            FadeCurve::EqualPower => (x * FRAC_PI_2).sin(),
+            FadeCurve::Logarithmic if x == 0. => 0.,
+            FadeCurve::Logarithmic => 10f64.powf(LOG_FLOOR_DB * (1. - x) / 20.),
+            FadeCurve::SCurve => x * x * (3. - 2. * x),
+        }
+    }
+
+    /// Amplitude gain of the outgoing deck after `progress` of the fade.
+    pub fn fade_out(self, progress: f64) -> f64 {
+        self.fade_in(1. - progress.clamp(0., 1.))
+    }
+}
+
+/// Converts an amplitude gain to mpv's `volume`, which is cubic: 50 is an
+/// eighth of the amplitude of 100.
+pub fn volume(gain: f64) -> f64 {
+    100. * gain.max(0.).cbrt()
+}
+
+/// When and how the next track is mixed in. All times are seconds of
+/// playback of the outgoing track.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CrossfadeConfig {
+    pub curve: FadeCurve,
+    /// How long before the end of the outgoing track the mix starts.
+    pub overlap: f64,
+    /// How long the outgoing deck takes to fade to silence.
+    pub fade_out: f64,
+    /// How long the incoming deck takes to reach full volume.
+    pub fade_in: f64,
+    /// The outgoing deck is cut to silence once its volume drops below this.
+    pub cutoff: f64,
+}
+
+impl Default for CrossfadeConfig {
+    fn default() -> Self {
+        Self {
+            curve: FadeCurve::LinearVolume,
+            overlap: 30.,
+            fade_out: 30.,
+            fade_in: 10.,
+            cutoff: 40.,
+        }
+    }
+}
+
+/// Volumes of both decks at one point of a crossfade.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FadeLevels {
+    pub from: f64,
+    pub to: f64,
+    /// Whether both decks reached their final level.
+    pub done: bool,
+}
+
+impl CrossfadeConfig {
+    /// Volumes of both decks `elapsed` seconds into the fade. Both come from
+    /// the same clock, so the two curves stay in step.
+    pub fn levels(&self, elapsed: f64) -> FadeLevels {
+        let progress = |length: f64| {
+            if length > 0. { elapsed / length } else { 1. }
+        };
+
+        let from = volume(self.curve.fade_out(progress(self.fade_out)));
+        let from = if from < self.cutoff { 0. } else { from };
+        let to = volume(self.curve.fade_in(progress(self.fade_in)));
+
+        FadeLevels {
+            from,
+            to,
+            done: from == 0. && elapsed >= self.fade_in,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVES: [FadeCurve; 5] = [
+        FadeCurve::LinearVolume,
+        FadeCurve::Linear,
+        FadeCurve::EqualPower,
+        FadeCurve::Logarithmic,
+        FadeCurve::SCurve,
+    ];
+
+    fn assert_close(actual: f64, expected: f64, what: &str) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{what}: {actual} instead of {expected}"
+        );
+    }
+
+    #[test]
+    fn default_fade_is_the_old_ramp() {
+        let config = CrossfadeConfig::default();
+
+        for step in 0..=80 {
+            let t = f64::from(step) / 2.;
+            let levels = config.levels(t);
+
+            let from = 100. * (1. - t / 30.);
+            let from = if from < 40. { 0. } else { from };
+            let to = (100. * t / 10.).min(100.);
+            assert_close(levels.from, from, &format!("outgoing after {t} s"));
+            assert_close(levels.to, to, &format!("incoming after {t} s"));
+        }
+    }
+
+    #[test]
+    fn curves_run_from_silence_to_full_gain() {
+        for curve in CURVES {
+            assert_close(curve.fade_in(0.), 0., &format!("{curve:?} at the start"));
+            assert_close(curve.fade_in(1.), 1., &format!("{curve:?} at the end"));
+            // Out of range progress is clamped
+            assert_close(curve.fade_in(-1.), 0., &format!("{curve:?} before"));
+            assert_close(curve.fade_in(2.), 1., &format!("{curve:?} after"));
+
+            let gains: Vec<f64> = (0..=1000)
+                .map(|step| curve.fade_in(f64::from(step) / 1000.))
+                .collect();
+            assert!(
+                gains.windows(2).all(|pair| pair[0] <= pair[1]),
+                "{curve:?} is not monotonic"
+            );
+            for (step, gain) in gains.iter().enumerate() {
+                let progress = step as f64 / 1000.;
+                assert_close(
+                    curve.fade_out(1. - progress),
+                    *gain,
+                    &format!("{curve:?} backwards at {progress}"),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn equal_power_keeps_the_summed_power() {
+        for step in 0..=100 {
+            let progress = f64::from(step) / 100.;
+            let fade_in = FadeCurve::EqualPower.fade_in(progress);
+            let fade_out = FadeCurve::EqualPower.fade_out(progress);
+            assert_close(
+                fade_in.powi(2) + fade_out.powi(2),
+                1.,
+                &format!("power at {progress}"),
+            );
+        }
+    }
+
+    #[test]
+    fn fade_is_done_once_both_decks_arrived() {
+        let config = CrossfadeConfig::default();
+        // The outgoing deck drops below the cutoff after 18 s
+        assert!(!config.levels(17.9).done);
+        assert!(config.levels(18.1).done);
+
+        // The outgoing deck is silent long before the incoming one is up
+        let config = CrossfadeConfig {
+            fade_out: 10.,
+            fade_in: 20.,
+            ..Default::default()
+        };
+        assert_eq!(config.levels(8.).from, 0.);
+        assert!(!config.levels(19.9).done);
+        assert!(config.levels(20.).done);
+        assert_eq!(config.levels(20.).to, 100.);
+    }
+}
@@ -0,0 +1,472 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader};
+use std::os::unix::net::UnixListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use crate::transport::{MemoryTransport, Transport};
+
+/// How often connections look for new commands and due events.
+const TICK: Duration = Duration::from_millis(20);
+
+/// Behaviour of a [`FakeMpv`] instance.
+#[derive(Debug, Clone)]
+pub struct FakeMpvOptions {
+    /// Playback speed relative to wall-clock time, e.g. 10.0 plays a three
+    /// minute track in 18 seconds.
+    pub speed: f64,
+    /// Duration reported for files not listed in `durations`.
+    pub default_duration: f64,
+    /// Duration per path as passed to `loadfile`.
+    pub durations: HashMap<String, f64>,
+}
+
+impl Default for FakeMpvOptions {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            default_duration: 180.0,
+            durations: HashMap::new(),
+        }
+    }
+}
+
+/// Test double for an `mpv --idle --input-ipc-server=...` process.
+///
+/// It listens on a Unix socket in a background thread and speaks the part of
+/// the JSON IPC the DJ uses: `loadfile`, `get_property`/`set_property` for
+/// `time-pos`, `duration`, `path`, `volume`, `pause` and `speed`,
+/// `observe_property`, `stop` and `quit`.
+/// Playback time is simulated, optionally faster than real time, and the
+/// `file-loaded`, `end-file` and `idle` events are sent like mpv does.
+///
+/// Besides the socket, clients can attach through a [`MemoryTransport`] or
+/// any other [`Transport`], see [`FakeMpv::serve`].
+pub struct FakeMpv {
+    socket_path: Option<String>,
+    player: Arc<Mutex<Player>>,
+    stop: Arc<AtomicBool>,
+    listener: Option<JoinHandle<()>>,
+}
+
+impl FakeMpv {
+    pub fn spawn(socket_path: &str, options: FakeMpvOptions) -> io::Result<Self> {
+        let _ = std::fs::remove_file(socket_path);
+        let listener = UnixListener::bind(socket_path)?;
+        listener.set_nonblocking(true)?;
+
+        let player = Arc::new(Mutex::new(Player::new(options)));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let player = Arc::clone(&player);
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || accept_loop(listener, player, stop))
+        };
+
+        Ok(Self {
+            socket_path: Some(socket_path.to_string()),
+            player,
+            stop,
+            listener: Some(handle),
+        })
+    }
+
+    /// A fake without a socket, only reachable through [`FakeMpv::connect`].
+    pub fn in_memory(options: FakeMpvOptions) -> Self {
+        Self {
+            socket_path: None,
+            player: Arc::new(Mutex::new(Player::new(options))),
+            stop: Arc::new(AtomicBool::new(false)),
+            listener: None,
+        }
+    }
+
+    /// Opens a connection that does not go through any socket.
+    pub fn connect(&self) -> MemoryTransport {
+        let (client, server) = MemoryTransport::pair();
+        self.serve(server);
+
+        client
+    }
+
+    /// Answers on a connection opened elsewhere, e.g. one accepted on a TCP
+    /// port, in a background thread.
+    pub fn serve(&self, stream: impl Transport + Send + 'static) {
+        let player = Arc::clone(&self.player);
+        let stop = Arc::clone(&self.stop);
+        thread::spawn(move || {
+            if let Err(e) = serve(stream, player, stop) {
+                eprintln!("fake mpv: connection failed: {e}");
+            }
+        });
+    }
+
+    /// `false` once a client sent `quit` or [`FakeMpv::stop`] was called.
+    pub fn is_running(&self) -> bool {
+        !self.stop.load(Ordering::SeqCst)
+    }
+
+    /// Every volume set so far as `(time-pos, volume)`, to check fade ramps.
+    pub fn volume_log(&self) -> Vec<(f64, f64)> {
+        self.player.lock().unwrap().volume_log.clone()
+    }
+
+    /// Paths passed to `loadfile`, in order.
+    pub fn loaded_files(&self) -> Vec<String> {
+        self.player.lock().unwrap().loaded_files.clone()
+    }
+
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(handle) = self.listener.take() {
+            let _ = handle.join();
+        }
+        if let Some(socket_path) = self.socket_path.take() {
+            let _ = std::fs::remove_file(socket_path);
+        }
+    }
+}
+
+impl Drop for FakeMpv {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+struct Playing {
+    path: String,
+    duration: f64,
+    position: f64,
+    since: Instant,
+}
+
+struct Player {
+    options: FakeMpvOptions,
+    playing: Option<Playing>,
+    volume: f64,
+    pause: bool,
+    speed: f64,
+    subscribers: Vec<Sender<serde_json::Value>>,
+    volume_log: Vec<(f64, f64)>,
+    loaded_files: Vec<String>,
+}
+
+impl Player {
+    fn new(options: FakeMpvOptions) -> Self {
+        Self {
+            options,
+            playing: None,
+            volume: 100.0,
+            pause: false,
+            speed: 1.0,
+            subscribers: Vec::new(),
+            volume_log: Vec::new(),
+            loaded_files: Vec::new(),
+        }
+    }
+
+    fn time_pos(&self) -> Option<f64> {
+        self.playing.as_ref().map(|playing| {
+            if self.pause {
+                return playing.position;
+            }
+            let elapsed = playing.since.elapsed().as_secs_f64() * self.options.speed * self.speed;
+            (playing.position + elapsed).min(playing.duration)
+        })
+    }
+
+    /// Freezes the simulated clock at its current position, so a change of
+    /// `pause` or `speed` only affects playback from now on.
+    fn rebase_clock(&mut self) {
+        if let Some(time_pos) = self.time_pos()
+            && let Some(playing) = self.playing.as_mut()
+        {
+            playing.position = time_pos;
+            playing.since = Instant::now();
+        }
+    }
+
+    fn broadcast(&mut self, event: serde_json::Value) {
+        self.subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Ends the current file once the simulated clock reached its duration.
+    fn tick(&mut self) {
+        if let (Some(time_pos), Some(playing)) = (self.time_pos(), &self.playing)
+            && time_pos >= playing.duration
+        {
+            self.playing = None;
+            self.broadcast(json!({ "event": "end-file", "reason": "eof" }));
+            self.broadcast(json!({ "event": "idle" }));
+        }
+    }
+
+    fn property(&self, name: &str) -> Result<serde_json::Value, &'static str> {
+        match name {
+            "volume" => Ok(json!(self.volume)),
+            "pause" => Ok(json!(self.pause)),
+            "speed" => Ok(json!(self.speed)),
+            "time-pos" => self
+                .time_pos()
+                .map(|t| json!(t))
+                .ok_or("property unavailable"),
+            "duration" => self
+                .playing
+                .as_ref()
+                .map(|p| json!(p.duration))
+                .ok_or("property unavailable"),
+            "path" => self
+                .playing
+                .as_ref()
+                .map(|p| json!(p.path))
+                .ok_or("property unavailable"),
+            _ => Err("property not found"),
+        }
+    }
+
+    fn set_property(&mut self, name: &str, value: &serde_json::Value) -> Result<(), &'static str> {
+        match name {
+            "volume" => {
+                self.volume = value
+                    .as_f64()
+                    .ok_or("unsupported format for accessing property")?;
+                let time_pos = self.time_pos().unwrap_or(0.);
+                self.volume_log.push((time_pos, self.volume));
+            }
+            "time-pos" => {
+                let position = value
+                    .as_f64()
+                    .ok_or("unsupported format for accessing property")?;
+                let playing = self.playing.as_mut().ok_or("property unavailable")?;
+                playing.position = position.clamp(0., playing.duration);
+                playing.since = Instant::now();
+            }
+            "pause" => {
+                let pause = value
+                    .as_bool()
+                    .ok_or("unsupported format for accessing property")?;
+                self.rebase_clock();
+                self.pause = pause;
+            }
+            "speed" => {
+                let speed = value
+                    .as_f64()
+                    .ok_or("unsupported format for accessing property")?;
+                self.rebase_clock();
+                self.speed = speed;
+            }
+            // Window placement has no effect on a headless fake
+            "fullscreen" | "fs-screen" => {}
+            _ => return Err("property not found"),
+        }
+
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        if self.playing.take().is_some() {
+            self.broadcast(json!({ "event": "end-file", "reason": "stop" }));
+            self.broadcast(json!({ "event": "idle" }));
+        }
+    }
+
+    fn loadfile(&mut self, path: &str) {
+        if self.playing.is_some() {
+            self.broadcast(json!({ "event": "end-file", "reason": "stop" }));
+        }
+
+        let duration = self
+            .options
+            .durations
+            .get(path)
+            .copied()
+            .unwrap_or(self.options.default_duration);
+        self.playing = Some(Playing {
+            path: path.to_string(),
+            duration,
+            position: 0.,
+            since: Instant::now(),
+        });
+        self.loaded_files.push(path.to_string());
+
+        self.broadcast(json!({ "event": "start-file" }));
+        self.broadcast(json!({ "event": "file-loaded" }));
+    }
+}
+
+fn accept_loop(listener: UnixListener, player: Arc<Mutex<Player>>, stop: Arc<AtomicBool>) {
+    let mut connections = Vec::new();
+
+    while !stop.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let player = Arc::clone(&player);
+                let stop = Arc::clone(&stop);
+                connections.push(thread::spawn(move || {
+                    if let Err(e) = serve(stream, player, stop) {
+                        eprintln!("fake mpv: connection failed: {e}");
+                    }
+                }));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(TICK),
+            Err(e) => {
+                eprintln!("fake mpv: accept failed: {e}");
+                break;
+            }
+        }
+    }
+
+    for connection in connections {
+        let _ = connection.join();
+    }
+}
+
+fn serve<T: Transport>(
+    mut stream: T,
+    player: Arc<Mutex<Player>>,
+    stop: Arc<AtomicBool>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(TICK))?;
+    let mut stream = BufReader::new(stream);
+
+    let (sender, events): (_, Receiver<serde_json::Value>) = mpsc::channel();
+    player.lock().unwrap().subscribers.push(sender);
+
+    // observe id -> (property name, last value sent)
+    let mut observed: HashMap<u64, (String, serde_json::Value)> = HashMap::new();
+    let mut line = Vec::new();
+
+    while !stop.load(Ordering::SeqCst) {
+        match stream.read_until(b'\n', &mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) if line.last() == Some(&b'\n') => {
+                let request: serde_json::Value = serde_json::from_slice(&line).unwrap_or_default();
+                line.clear();
+
+                let mut player = player.lock().unwrap();
+                let reply = handle(&request, &mut player, &mut observed, &stop);
+                writeln!(stream.get_mut(), "{reply}")?;
+            }
+            Ok(_) => {}
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            }
+            Err(e) => return Err(e),
+        }
+
+        let mut player = player.lock().unwrap();
+        player.tick();
+
+        for event in events.try_iter() {
+            writeln!(stream.get_mut(), "{event}")?;
+        }
+
+        for (id, (name, last)) in observed.iter_mut() {
+            let value = player.property(name).unwrap_or_default();
+            if value != *last {
+                *last = value.clone();
+                writeln!(
+                    stream.get_mut(),
+                    "{}",
+                    json!({ "event": "property-change", "id": id, "name": name, "data": value })
+                )?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn handle(
+    request: &serde_json::Value,
+    player: &mut Player,
+    observed: &mut HashMap<u64, (String, serde_json::Value)>,
+    stop: &AtomicBool,
+) -> serde_json::Value {
+    let request_id = request.get("request_id").cloned().unwrap_or(json!(0));
+    let args = request
+        .get("command")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+    let arg = |i: usize| args.get(i).cloned().unwrap_or_default();
+
+    let result: Result<serde_json::Value, &str> = match arg(0).as_str().unwrap_or_default() {
+        "loadfile" => match arg(1).as_str() {
+            Some(path) => {
+                player.loadfile(path);
+                Ok(serde_json::Value::Null)
+            }
+            None => Err("invalid parameter"),
+        },
+        "get_property" => player.property(arg(1).as_str().unwrap_or_default()),
+        "set_property" => player
+            .set_property(arg(1).as_str().unwrap_or_default(), &arg(2))
+            .map(|_| serde_json::Value::Null),
+        "observe_property" => match (arg(1).as_u64(), arg(2).as_str()) {
+            (Some(id), Some(name)) => {
+                // An impossible last value makes the next tick send the current one
+                observed.insert(id, (name.to_string(), json!({})));
+                Ok(serde_json::Value::Null)
+            }
+            _ => Err("invalid parameter"),
+        },
+        "stop" => {
+            player.stop();
+            Ok(serde_json::Value::Null)
+        }
+        "quit" => {
+            stop.store(true, Ordering::SeqCst);
+            Ok(serde_json::Value::Null)
+        }
+        _ => Err("invalid parameter"),
+    };
+
+    match result {
+        Ok(data) => json!({ "data": data, "request_id": request_id, "error": "success" }),
+        Err(error) => json!({ "request_id": request_id, "error": error }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::commands::MpvClient;
+
+    #[test]
+    fn records_volume_ramp_and_loaded_files() {
+        let fake = FakeMpv::in_memory(FakeMpvOptions {
+            speed: 1000.,
+            durations: HashMap::from([("b.mp4".to_string(), 60.)]),
+            ..Default::default()
+        });
+        let mut client = MpvClient::new(fake.connect(), "memory").unwrap();
+
+        assert_eq!(client.load_video(Path::new("a.mp4")).unwrap(), 180.);
+        for level in [0., 25., 50., 75., 100.] {
+            client.set_volume(level).unwrap();
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let log = fake.volume_log();
+        let volumes: Vec<f64> = log.iter().map(|&(_, volume)| volume).collect();
+        assert_eq!(volumes, [0., 25., 50., 75., 100.]);
+        // Each step is logged at a later playback time than the one before
+        assert!(log.windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+        // Three minutes at a thousand times the speed are over in no time
+        assert!(client.wait_for_end_file(Duration::from_secs(2)));
+
+        assert_eq!(client.load_video(Path::new("b.mp4")).unwrap(), 60.);
+        assert_eq!(fake.loaded_files(), ["a.mp4", "b.mp4"]);
+    }
+}
@@ -0,0 +1,269 @@
+use std::f64::consts::PI;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::tempo::SAMPLE_RATE;
+
+/// Samples per analysis window, about 0.37 s.
+const WINDOW: usize = 4096;
+/// Samples between the starts of two windows.
+const HOP: usize = 8192;
+/// Lowest and highest MIDI note that counts towards the chroma.
+const LOWEST_NOTE: u8 = 36;
+const HIGHEST_NOTE: u8 = 95;
+
+/// Krumhansl-Kessler key profiles, starting at the tonic.
+const MAJOR_PROFILE: [f64; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f64; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+/// A musical key in Camelot notation, e.g. `8A` for A minor and `8B` for
+/// C major. Neighbouring numbers are a fifth apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Camelot {
+    /// 1 to 12 around the wheel.
+    pub number: u8,
+    /// Minor keys are `A`, major keys `B`.
+    pub minor: bool,
+}
+
+impl Camelot {
+    /// The key with the given tonic, 0 for C up to 11 for B.
+    pub fn from_tonic(pitch_class: usize, minor: bool) -> Self {
+        // Minor keys share the number of their relative major
+        let major = if minor {
+            (pitch_class + 3) % 12
+        } else {
+            pitch_class % 12
+        };
+        let number = ((major * 7) % 12 + 7) % 12 + 1;
+
+        Camelot {
+            number: number as u8,
+            minor,
+        }
+    }
+
+    /// Whether a mix between the keys sounds consonant: the same key, one
+    /// step around the wheel, or the relative major or minor.
+    pub fn is_compatible(self, other: Camelot) -> bool {
+        let distance = (i16::from(self.number) - i16::from(other.number)).rem_euclid(12);
+
+        if self.minor == other.minor {
+            matches!(distance, 0 | 1 | 11)
+        } else {
+            distance == 0
+        }
+    }
+}
+
+impl fmt::Display for Camelot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.number, if self.minor { 'A' } else { 'B' })
+    }
+}
+
+impl TryFrom<String> for Camelot {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let value = value.trim();
+        let invalid = || format!("invalid Camelot key: {value}");
+
+        let letter = value.chars().last().ok_or_else(invalid)?;
+        let number: u8 = value[..value.len() - letter.len_utf8()]
+            .parse()
+            .map_err(|_| invalid())?;
+        let minor = match letter {
+            'A' | 'a' => true,
+            'B' | 'b' => false,
+            _ => return Err(invalid()),
+        };
+        if !(1..=12).contains(&number) {
+            return Err(invalid());
+        }
+
+        Ok(Camelot { number, minor })
+    }
+}
+
+impl From<Camelot> for String {
+    fn from(key: Camelot) -> Self {
+        key.to_string()
+    }
+}
+
+/// Estimates the key of mono audio at [`SAMPLE_RATE`] by matching its
+/// chroma, the energy per pitch class, against the major and minor key
+/// profiles of every tonic.
+pub fn detect(samples: &[i16]) -> Option<Camelot> {
+    let chroma = chroma(samples)?;
+
+    let correlation = |profile: &[f64; 12], tonic: usize| -> f64 {
+        let rotated: Vec<f64> = (0..12).map(|i| profile[(i + 12 - tonic) % 12]).collect();
+        pearson(&chroma, &rotated)
+    };
+
+    (0..12)
+        .flat_map(|tonic| {
+            [
+                (tonic, false, correlation(&MAJOR_PROFILE, tonic)),
+                (tonic, true, correlation(&MINOR_PROFILE, tonic)),
+            ]
+        })
+        .max_by(|(_, _, a), (_, _, b)| a.total_cmp(b))
+        .map(|(tonic, minor, _)| Camelot::from_tonic(tonic, minor))
+}
+
+/// Energy per pitch class over the whole track, measured with one Goertzel
+/// filter per note on Hann-windowed stretches of audio.
+fn chroma(samples: &[i16]) -> Option<[f64; 12]> {
+    let window: Vec<f64> = (0..WINDOW)
+        .map(|i| 0.5 - 0.5 * (2. * PI * i as f64 / WINDOW as f64).cos())
+        .collect();
+    let coefficients: Vec<(usize, f64)> = (LOWEST_NOTE..=HIGHEST_NOTE)
+        .map(|note| {
+            let frequency = 440. * 2f64.powf((f64::from(note) - 69.) / 12.);
+            let omega = 2. * PI * frequency / f64::from(SAMPLE_RATE);
+            (usize::from(note) % 12, 2. * omega.cos())
+        })
+        .collect();
+
+    let mut chroma = [0.; 12];
+    let mut frames = 0;
+    for start in (0..samples.len().saturating_sub(WINDOW)).step_by(HOP) {
+        let frame: Vec<f64> = samples[start..start + WINDOW]
+            .iter()
+            .zip(&window)
+            .map(|(&s, w)| f64::from(s) * w)
+            .collect();
+
+        for &(pitch_class, coefficient) in &coefficients {
+            let (mut s1, mut s2) = (0., 0.);
+            for &x in &frame {
+                let s0 = x + coefficient * s1 - s2;
+                s2 = s1;
+                s1 = s0;
+            }
+            let power = s1 * s1 + s2 * s2 - coefficient * s1 * s2;
+            chroma[pitch_class] += (1. + power).ln();
+        }
+        frames += 1;
+    }
+
+    (frames > 0).then_some(chroma)
+}
+
+fn pearson(a: &[f64], b: &[f64]) -> f64 {
+    let mean = |v: &[f64]| v.iter().sum::<f64>() / v.len() as f64;
+    let (mean_a, mean_b) = (mean(a), mean(b));
+
+    let covariance: f64 = a
+        .iter()
+        .zip(b)
+        .map(|(x, y)| (x - mean_a) * (y - mean_b))
+        .sum();
+    let spread_a: f64 = a.iter().map(|x| (x - mean_a).powi(2)).sum();
+    let spread_b: f64 = b.iter().map(|y| (y - mean_b).powi(2)).sum();
+
+    covariance / (spread_a * spread_b).sqrt().max(f64::EPSILON)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(value: &str) -> Camelot {
+        Camelot::try_from(value.to_string()).unwrap()
+    }
+
+    #[test]
+    fn tonics_are_placed_on_the_wheel() {
+        // C, G, D, A, E and B major, then the minor keys
+        let cases = [
+            ((0, false), "8B"),
+            ((7, false), "9B"),
+            ((2, false), "10B"),
+            ((9, false), "11B"),
+            ((4, false), "12B"),
+            ((11, false), "1B"),
+            ((5, false), "7B"),
+            ((9, true), "8A"),
+            ((4, true), "9A"),
+            ((2, true), "7A"),
+            ((1, true), "12A"),
+            ((8, true), "1A"),
+        ];
+        for ((tonic, minor), expected) in cases {
+            assert_eq!(
+                Camelot::from_tonic(tonic, minor).to_string(),
+                expected,
+                "tonic {tonic}, minor {minor}"
+            );
+        }
+
+        // Every key has a place of its own
+        let mut keys: Vec<String> = (0..12)
+            .flat_map(|tonic| [false, true].map(|minor| Camelot::from_tonic(tonic, minor)))
+            .map(String::from)
+            .collect();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), 24);
+    }
+
+    #[test]
+    fn neighbouring_keys_are_compatible() {
+        let compatible = |a: &str, b: &str| key(a).is_compatible(key(b));
+
+        assert!(compatible("8A", "8A"));
+        assert!(compatible("8A", "9A"));
+        assert!(compatible("8A", "7A"));
+        assert!(compatible("8A", "8B"));
+        assert!(compatible("8B", "8A"));
+        // Around the end of the wheel
+        assert!(compatible("12A", "1A"));
+        assert!(compatible("1B", "12B"));
+
+        assert!(!compatible("8A", "10A"));
+        assert!(!compatible("8A", "9B"));
+        assert!(!compatible("12A", "1B"));
+        assert!(!compatible("8B", "2B"));
+    }
+
+    #[test]
+    fn keys_are_read_and_written_in_camelot_notation() {
+        assert_eq!(
+            key("8A"),
+            Camelot {
+                number: 8,
+                minor: true
+            }
+        );
+        assert_eq!(
+            key(" 12b "),
+            Camelot {
+                number: 12,
+                minor: false
+            }
+        );
+        for number in 1..=12 {
+            for letter in ["A", "B"] {
+                let value = format!("{number}{letter}");
+                assert_eq!(String::from(key(&value)), value);
+            }
+        }
+
+        for invalid in ["0A", "13B", "8C", "A", "8", "", "-1A", "8AA"] {
+            assert!(
+                Camelot::try_from(invalid.to_string()).is_err(),
+                "{invalid:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,19 @@
+pub mod analysis;
+pub mod commands;
+pub mod config;
+pub mod deck;
+pub mod energy;
+pub mod error;
+pub mod fade;
+pub mod fake_mpv;
+pub mod key;
+pub mod loudness;
+pub mod media_files;
+pub mod properties;
+pub mod rating;
+pub mod schedule;
+pub mod selection;
+pub mod shutdown;
+pub mod state_machine;
+pub mod tempo;
+pub mod transport;
@@ -0,0 +1,152 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::media_files;
+
+/// Where measurements are cached, next to `media-files.csv`.
+const LOUDNESS_CSV: &str = "loudness.csv";
+
+/// EBU R128 measurement of one file.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Loudness {
+    pub path: PathBuf,
+    /// Integrated loudness in LUFS.
+    pub integrated: f64,
+    /// True peak in dBTP.
+    pub true_peak: f64,
+}
+
+impl Loudness {
+    /// The gain in dB that brings the file to `target` LUFS without pushing
+    /// its true peak above `ceiling` dBTP.
+    pub fn gain_db(&self, target: f64, ceiling: f64) -> f64 {
+        (target - self.integrated).min(ceiling - self.true_peak)
+    }
+}
+
+/// How every track is levelled before it is mixed.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LoudnessConfig {
+    pub enabled: bool,
+    /// Integrated loudness all tracks are brought to, in LUFS.
+    pub target: f64,
+    /// Highest true peak a boost may produce, in dBTP.
+    pub max_true_peak: f64,
+}
+
+impl Default for LoudnessConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            target: -14.,
+            max_true_peak: -1.,
+        }
+    }
+}
+
+impl LoudnessConfig {
+    /// Amplitude gain for the file, measured in the cache in `data_dir`.
+    /// Files that were never measured are played as they are.
+    pub fn gain(&self, data_dir: &Path, path: &Path) -> f64 {
+        if !self.enabled {
+            return 1.;
+        }
+
+        let cache = match read_cache(data_dir) {
+            Ok(cache) => cache,
+            Err(e) => {
+                eprintln!("Cannot read {LOUDNESS_CSV}: {e}");
+                return 1.;
+            }
+        };
+
+        match cache.iter().find(|loudness| loudness.path == path) {
+            Some(loudness) => {
+                let gain_db = loudness.gain_db(self.target, self.max_true_peak);
+                eprintln!(
+                    "loudness of {}: {:.1} LUFS, gain {gain_db:+.1} dB",
+                    path.display(),
+                    loudness.integrated
+                );
+                10f64.powf(gain_db / 20.)
+            }
+            None => 1.,
+        }
+    }
+}
+
+/// Every measurement so far, empty if nothing was measured yet.
+pub fn read_cache(data_dir: &Path) -> Result<Vec<Loudness>, Box<dyn Error>> {
+    let path = data_dir.join(LOUDNESS_CSV);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut rdr = csv::Reader::from_path(path)?;
+    let cache = rdr.deserialize().collect::<Result<_, _>>()?;
+
+    Ok(cache)
+}
+
+/// Adds the measurement to the cache, replacing an older one of the file.
+pub fn store(data_dir: &Path, loudness: Loudness) -> Result<(), Box<dyn Error>> {
+    let mut cache = read_cache(data_dir)?;
+    cache.retain(|cached| cached.path != loudness.path);
+    cache.push(loudness);
+
+    media_files::write_csv_atomically(&data_dir.join(LOUDNESS_CSV), &cache)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measured(path: &str, integrated: f64, true_peak: f64) -> Loudness {
+        Loudness {
+            path: PathBuf::from(path),
+            integrated,
+            true_peak,
+        }
+    }
+
+    #[test]
+    fn true_peak_ceiling_limits_a_boost() {
+        // 6 dB below the target, but only 2 dB of headroom
+        assert_eq!(measured("a.mp4", -20., -3.).gain_db(-14., -1.), 2.);
+        // Enough headroom for the whole boost
+        assert_eq!(measured("a.mp4", -20., -10.).gain_db(-14., -1.), 6.);
+        // Already over the ceiling: turned down even below the target
+        assert_eq!(measured("a.mp4", -14., 1.).gain_db(-14., -1.), -2.);
+        // Too loud: the cut alone counts
+        assert_eq!(measured("a.mp4", -8., 0.).gain_db(-14., -1.), -6.);
+    }
+
+    #[test]
+    fn gain_comes_from_the_cache() {
+        let dir = std::env::temp_dir().join(format!("mpv-dj-loudness-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        store(&dir, measured("a.mp4", -20., -3.)).unwrap();
+        store(&dir, measured("b.mp4", -8., -3.)).unwrap();
+        // A new measurement replaces the old one
+        store(&dir, measured("b.mp4", -20., -10.)).unwrap();
+        assert_eq!(read_cache(&dir).unwrap().len(), 2);
+
+        let config = LoudnessConfig::default();
+        let gain = |path: &str| config.gain(&dir, Path::new(path));
+        assert!((gain("a.mp4") - 10f64.powf(2. / 20.)).abs() < 1e-9);
+        assert!((gain("b.mp4") - 10f64.powf(6. / 20.)).abs() < 1e-9);
+        assert_eq!(gain("unknown.mp4"), 1.);
+
+        let disabled = LoudnessConfig {
+            enabled: false,
+            ..Default::default()
+        };
+        assert_eq!(disabled.gain(&dir, Path::new("a.mp4")), 1.);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,20 +1,66 @@
 use std::path::Path;
+use std::time::Duration;
 
-mod commands;
-mod media_files;
-mod state_machine;
+use mpv_dj_rs::config::Config;
+use mpv_dj_rs::deck::Backend;
+use mpv_dj_rs::fake_mpv::FakeMpvOptions;
+use mpv_dj_rs::shutdown::Shutdown;
+use mpv_dj_rs::{analysis, media_files, state_machine};
 
 fn main() -> std::io::Result<()> {
-    let media_files_csv = "media-files.csv";
-    let categories_csv = "categories.csv";
+    let config = match Config::load(Path::new("config.json")) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Cannot read config.json, using the defaults: {e}");
+            Config::default()
+        }
+    };
 
-    if !Path::new(media_files_csv).exists() || !Path::new(categories_csv).exists() {
-        let media_files = media_files::load(Path::new("/home/micki/1tb/Music"));
+    let media_files_csv = config.data_dir.join(media_files::MEDIA_FILES_CSV);
+    let categories_csv = config.data_dir.join(media_files::CATEGORIES_CSV);
 
-        media_files::write_media_files_to_csv(&media_files, media_files_csv, categories_csv)?;
+    let music_dir = Path::new("/home/micki/1tb/Music");
+
+    if !media_files_csv.exists() || !categories_csv.exists() {
+        let media_files = media_files::load(music_dir);
+
+        media_files::write_media_files_to_csv(&media_files, &media_files_csv, &categories_csv)?;
+    }
+
+    // `analyze` suggests cue points for the library instead of playing it
+    if std::env::args().nth(1).as_deref() == Some("analyze") {
+        if let Err(e) = analysis::analyze_library(music_dir, &config.data_dir) {
+            eprintln!("Analysis failed: {e}");
+        }
+        return Ok(());
     }
 
-    state_machine::play();
+    // `--fake-mpv=SPEED` plays against simulated decks instead of real mpv,
+    // `--attach` uses players that already listen on the deck sockets
+    let backend = match std::env::args().find_map(|arg| {
+        arg.strip_prefix("--fake-mpv")
+            .map(|speed| speed.trim_start_matches('=').to_string())
+    }) {
+        Some(speed) => Backend::Fake(FakeMpvOptions {
+            speed: speed.parse().unwrap_or(1.0),
+            ..Default::default()
+        }),
+        None if std::env::args().any(|arg| arg == "--attach") => Backend::Attach,
+        None => Backend::Mpv,
+    };
+
+    // `--fade-out=SECONDS` is how long the set fades out on SIGINT/SIGTERM
+    let fade_out = std::env::args()
+        .find_map(|arg| {
+            arg.strip_prefix("--fade-out=")
+                .and_then(|seconds| seconds.parse().ok())
+        })
+        .map(Duration::from_secs_f64)
+        .unwrap_or(Duration::from_secs(5));
+
+    let shutdown = Shutdown::install()?;
+
+    state_machine::play(&backend, &shutdown, &config, fade_out);
 
     eprintln!("main finished, quit now");
 
@@ -5,15 +5,34 @@ use std::io::{self};
 use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use chrono::Local;
 use csv::Writer;
-use rand::seq::IteratorRandom;
 use rand::{SeedableRng, rngs::StdRng};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
-struct LastChoice {
-    media_file: MediaFile,
-    times_chosen: u32,
+use crate::energy::SetPlan;
+use crate::key::Camelot;
+use crate::rating::{self, Rating};
+use crate::schedule::Schedule;
+use crate::selection::{History, Library, Picker, StrategyKind};
+
+/// Every file of the library with its play counts and analysis results.
+pub const MEDIA_FILES_CSV: &str = "media-files.csv";
+/// Every category with its airtime and block settings.
+pub const CATEGORIES_CSV: &str = "categories.csv";
+/// Where [`LastChoice`] is kept.
+const LAST_CHOICE_JSON: &str = "last_choice.json";
+
+/// The file chosen last and how many files in a row were chosen from its
+/// category, stored in `last_choice.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LastChoice {
+    pub media_file: MediaFile,
+    pub times_chosen: u32,
+    /// Number of tracks the current block of the category should last,
+    /// drawn when the block started.
+    #[serde(default)]
+    pub block_length: Option<u32>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -21,15 +40,161 @@ pub struct MediaFile {
     pub path: PathBuf,
     pub category: String,
     pub played: u32,
+    /// Seconds to skip at the start, e.g. a silent intro.
+    #[serde(default)]
+    pub cue_in: Option<f64>,
+    /// Seconds into the file where the track is over for the mix, e.g.
+    /// before a spoken outro or end credits.
+    #[serde(default)]
+    pub cue_out: Option<f64>,
+    /// Tempo in beats per minute.
+    #[serde(default)]
+    pub bpm: Option<f64>,
+    /// Position of the first beat in seconds, the start of the beat grid.
+    #[serde(default)]
+    pub first_beat: Option<f64>,
+    /// Musical key, e.g. `8A`.
+    #[serde(default)]
+    pub key: Option<Camelot>,
+    /// When the file was played last, in seconds since the Unix epoch.
+    #[serde(default)]
+    pub last_played: Option<u64>,
+    /// How energetic the track is, from 1 for calm to 10 for peak time.
+    /// Set by hand or estimated by the analysis.
+    #[serde(default)]
+    pub energy: Option<f64>,
+    #[serde(default)]
+    pub artist: Option<String>,
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Whether the analysis decoded the file, so what it could not find is
+    /// not searched for again.
+    #[serde(default)]
+    pub analyzed: bool,
+    /// Kept in `ratings.csv`, see [`rating::apply`].
+    #[serde(skip)]
+    pub rating: Option<Rating>,
+}
+
+impl MediaFile {
+    pub fn is_banned(&self) -> bool {
+        self.rating == Some(Rating::Ban)
+    }
+
+    /// Whether both files are known to be by the same artist.
+    pub fn same_artist(&self, other: &MediaFile) -> bool {
+        match (&self.artist, &other.artist) {
+            (Some(a), Some(b)) => a.trim().eq_ignore_ascii_case(b.trim()),
+            _ => false,
+        }
+    }
+}
+
+/// Artist and title of a file named `Artist - Title.mp4`.
+pub fn artist_and_title(path: &Path) -> Option<(String, String)> {
+    let stem = path.file_stem()?.to_str()?;
+    let (artist, title) = stem.split_once(" - ")?;
+    let (artist, title) = (artist.trim(), title.trim());
+    if artist.is_empty() || title.is_empty() {
+        return None;
+    }
+
+    Some((artist.to_string(), title.to_string()))
+}
+
+/// How the next file is chosen.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SelectionConfig {
+    pub strategy: StrategyKind,
+    /// Prefer files whose key mixes well with the current one.
+    pub harmonic: bool,
+    /// Share of the airtime each category should get, e.g.
+    /// `{"pop": 40, "rock": 30, "80s": 30}`. Only the ratios matter.
+    pub airtime: HashMap<String, f64>,
+    pub no_repeat: NoRepeat,
+    /// Categories shown, hidden or reweighted by time of day and weekday.
+    pub schedule: Schedule,
+    /// Energy curve the set should follow.
+    pub set_plan: SetPlan,
+    /// Fewest tracks between two by the same artist, 0 for no limit.
+    pub artist_separation: usize,
+}
+
+/// How long a played file is kept out of the selection. Both limits may be
+/// set; a file is out while either of them holds.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct NoRepeat {
+    /// Not within this many hours of its last play.
+    pub hours: Option<f64>,
+    /// Not among this many files played last, the one on air included.
+    pub tracks: Option<usize>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
-struct Category {
-    category: String,
-    duration_overall: u64,
-    current_duration: u64,
-    count: u32,
-    visible: bool,
+pub struct Category {
+    pub category: String,
+    pub duration_overall: u64,
+    pub current_duration: u64,
+    pub count: u32,
+    pub visible: bool,
+    /// Fewest tracks in a row from this category.
+    #[serde(default)]
+    pub block_min: Option<u32>,
+    /// Most tracks in a row from this category.
+    #[serde(default)]
+    pub block_max: Option<u32>,
+    /// Minutes of `current_duration` after which a block ends.
+    #[serde(default)]
+    pub block_minutes: Option<f64>,
+    /// Play cycles completed so far. A cycle ends once every file of the
+    /// category was played; the `played` counters start at 0 again then.
+    #[serde(default)]
+    pub epoch: u32,
+}
+
+/// Block length of categories that do not set one.
+const DEFAULT_BLOCK_LENGTH: u32 = 4;
+
+impl Category {
+    /// Fewest and most tracks in a row. A block measured in minutes is only
+    /// limited by what is set explicitly, a block measured in tracks lasts
+    /// four tracks unless set otherwise.
+    pub fn block_range(&self) -> (u32, u32) {
+        let (min, max) = if self.block_minutes.is_some() {
+            (
+                self.block_min.unwrap_or(1),
+                self.block_max.unwrap_or(u32::MAX),
+            )
+        } else {
+            let min = self
+                .block_min
+                .or(self.block_max)
+                .unwrap_or(DEFAULT_BLOCK_LENGTH);
+            let max = self
+                .block_max
+                .or(self.block_min)
+                .unwrap_or(DEFAULT_BLOCK_LENGTH);
+            (min, max)
+        };
+
+        (min.max(1), max.max(min.max(1)))
+    }
+
+    /// Whether a block of this category is over after `times_chosen` tracks.
+    /// `block_length` is the length drawn for the block, if any.
+    pub fn block_over(&self, times_chosen: u32, block_length: Option<u32>) -> bool {
+        let (min, max) = self.block_range();
+
+        match self.block_minutes {
+            Some(minutes) => {
+                times_chosen >= max
+                    || (times_chosen >= min && self.current_duration as f64 >= minutes * 60.)
+            }
+            None => times_chosen >= block_length.unwrap_or(max).clamp(min, max),
+        }
+    }
 }
 
 pub fn load(music_dir: &Path) -> Vec<MediaFile> {
@@ -38,10 +203,10 @@ pub fn load(music_dir: &Path) -> Vec<MediaFile> {
     if let Ok(entries) = fs::read_dir(music_dir) {
         for entry in entries.flatten() {
             let path = entry.path();
-            if path.is_dir() {
-                if let Some(category_name) = path.file_name().and_then(|n| n.to_str()) {
-                    collect_media_files(&path, category_name, &mut media_files);
-                }
+            if path.is_dir()
+                && let Some(category_name) = path.file_name().and_then(|n| n.to_str())
+            {
+                collect_media_files(&path, category_name, &mut media_files);
             }
         }
     }
@@ -51,14 +216,28 @@ pub fn load(music_dir: &Path) -> Vec<MediaFile> {
 
 pub fn write_media_files_to_csv(
     media_files: &Vec<MediaFile>,
-    csv_path: &str,
-    category_csv_path: &str,
+    csv_path: &Path,
+    category_csv_path: &Path,
 ) -> io::Result<()> {
     let file = File::create(csv_path)?;
     let mut wtr = Writer::from_writer(file);
 
     // Write the header
-    wtr.write_record(&["category", "path", "played"])?;
+    wtr.write_record([
+        "category",
+        "path",
+        "played",
+        "cue_in",
+        "cue_out",
+        "bpm",
+        "first_beat",
+        "key",
+        "last_played",
+        "energy",
+        "artist",
+        "title",
+        "analyzed",
+    ])?;
 
     // Count categories
     let mut category_counts: HashMap<String, usize> = HashMap::new();
@@ -68,10 +247,25 @@ pub fn write_media_files_to_csv(
         let category = media.category.clone();
         let path = (*media.path.to_string_lossy()).to_string();
         let played = media.played.to_string();
+        let optional = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
 
         *category_counts.entry(category.clone()).or_insert(0) += 1;
 
-        wtr.write_record(&[category, path, played])?;
+        wtr.write_record([
+            category,
+            path,
+            played,
+            optional(media.cue_in),
+            optional(media.cue_out),
+            optional(media.bpm),
+            optional(media.first_beat),
+            media.key.map(String::from).unwrap_or_default(),
+            media.last_played.map(|t| t.to_string()).unwrap_or_default(),
+            optional(media.energy),
+            media.artist.clone().unwrap_or_default(),
+            media.title.clone().unwrap_or_default(),
+            media.analyzed.to_string(),
+        ])?;
     }
 
     // Flush the writer to ensure all data is written
@@ -81,16 +275,30 @@ pub fn write_media_files_to_csv(
     let category_file = File::create(category_csv_path)?;
     let mut category_writer = Writer::from_writer(category_file);
 
-    category_writer.write_record(&[
+    category_writer.write_record([
         "category",
         "duration_overall",
         "current_duration",
         "count",
         "visible",
+        "block_min",
+        "block_max",
+        "block_minutes",
+        "epoch",
     ])?;
 
     for (category, count) in category_counts.iter() {
-        category_writer.write_record(&[category, "0", "0", &count.to_string(), "true"])?;
+        category_writer.write_record([
+            category,
+            "0",
+            "0",
+            &count.to_string(),
+            "true",
+            "",
+            "",
+            "",
+            "0",
+        ])?;
     }
 
     category_writer.flush()?;
@@ -104,133 +312,128 @@ fn collect_media_files(dir: &Path, category: &str, media_files: &mut Vec<MediaFi
             let path = entry.path();
             if path.is_dir() {
                 collect_media_files(&path, category, media_files);
-            } else if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-                if ext.eq_ignore_ascii_case("mp4") || ext.eq_ignore_ascii_case("webm") {
-                    media_files.push(MediaFile {
-                        path,
-                        category: category.to_string(),
-                        played: 0,
-                    });
-                }
+            } else if let Some(ext) = path.extension().and_then(|e| e.to_str())
+                && (ext.eq_ignore_ascii_case("mp4") || ext.eq_ignore_ascii_case("webm"))
+            {
+                let (artist, title) = artist_and_title(&path).unzip();
+                media_files.push(MediaFile {
+                    path,
+                    category: category.to_string(),
+                    played: 0,
+                    cue_in: None,
+                    cue_out: None,
+                    bpm: None,
+                    first_beat: None,
+                    key: None,
+                    last_played: None,
+                    energy: None,
+                    artist,
+                    title,
+                    analyzed: false,
+                    rating: None,
+                });
             }
         }
     }
 }
 
+/// Chooses the file to play after `current_media_file` with the strategy
+/// from `config` and remembers the choice in `last_choice.json` in
+/// `data_dir`. Files in
+/// the no-repeat window or by an artist played too recently are left out
+/// unless nothing else could be played, banned files are never chosen. A
+/// category whose files were all played starts a new cycle first, so the
+/// strategy keeps finding files within the category it chose. Only if that
+/// category has nothing besides the file on air does the pick leave it: then
+/// any file of a visible category is chosen. With a `target_energy`, files
+/// close to it are preferred.
 pub fn choose_media_file(
+    data_dir: &Path,
     current_media_file: Option<MediaFile>,
+    config: &SelectionConfig,
+    target_energy: Option<f64>,
 ) -> Result<Option<MediaFile>, Box<dyn Error>> {
-    let cat_file = File::open("categories.csv")?;
-    let media_file = File::open("media-files.csv")?;
-
+    let cat_file = File::open(data_dir.join(CATEGORIES_CSV))?;
     let mut rdr_cat = csv::Reader::from_reader(cat_file);
-    let mut rdr_media = csv::Reader::from_reader(media_file);
 
-    let categories: Vec<Category> = rdr_cat.deserialize().collect::<Result<_, _>>()?;
-    let media_files: Vec<MediaFile> = rdr_media.deserialize().collect::<Result<_, _>>()?;
-
-    let last_choice_path = "last_choice.json";
+    let mut library = Library {
+        media_files: read_media_files(data_dir)?,
+        categories: rdr_cat.deserialize().collect::<Result<_, _>>()?,
+    };
+    rating::apply(data_dir, &mut library.media_files)?;
+    start_new_epochs(data_dir, &mut library)?;
+    library.media_files.retain(|f| !f.is_banned());
+    let category_weights = config.schedule.apply(&mut library, Local::now());
 
-    let mut rng = create_seeded_rng();
+    let last_choice_path = data_dir.join(LAST_CHOICE_JSON);
 
-    let (category_to_use, times_chosen) = if Path::new(last_choice_path).exists() {
-        let last_choice_data = fs::read_to_string(last_choice_path)?;
-        let last_choice: LastChoice = serde_json::from_str(&last_choice_data)?;
+    let history = if last_choice_path.exists() {
+        let last_choice_data = fs::read_to_string(&last_choice_path)?;
+        History {
+            last_choice: Some(serde_json::from_str(&last_choice_data)?),
+        }
+    } else {
+        History::default()
+    };
 
-        if last_choice.times_chosen >= 4 {
-            // pick a different category
-            let other_categories: Vec<&Category> = categories
+    let mut picker = Picker::new(create_seeded_rng(), config)
+        .with_category_weights(category_weights)
+        .with_target_energy(target_energy);
+    let strategy = config.strategy.strategy();
+    let current = current_media_file.as_ref();
+    let allowed = library
+        .without_recently_played(&config.no_repeat, unix_time(), current)
+        .without_recent_artists(config.artist_separation, current);
+    let Some(selected) = strategy
+        .choose(&allowed, &history, current, &mut picker)
+        .or_else(|| {
+            eprintln!(
+                "nothing to play outside of the no-repeat window and artist separation, ignore them"
+            );
+            strategy.choose(&library, &history, current, &mut picker)
+        })
+        .or_else(|| {
+            eprintln!("nothing to play in the category but the file on air, take any visible file");
+            let candidates = library
+                .media_files
                 .iter()
-                .filter(|cat| cat.visible && cat.category != last_choice.media_file.category)
+                .filter(|f| {
+                    library.is_visible(&f.category)
+                        && current.is_none_or(|current| current.path != f.path)
+                })
                 .collect();
+            picker.pick(candidates, current)
+        })
+    else {
+        return Ok(None);
+    };
 
-            if let Some(new_cat) = other_categories.into_iter().choose(&mut rng) {
-                (new_cat.category.clone(), 1)
-            } else {
-                eprintln!("no other category available, so take the last one anyway");
-                (
-                    last_choice.media_file.category,
-                    last_choice.times_chosen + 1,
-                )
-            }
-        } else {
-            (
-                last_choice.media_file.category.clone(),
-                last_choice.times_chosen + 1,
-            )
+    // Save the new choice
+    let (times_chosen, block_length) = match history.last_choice {
+        Some(last_choice) if last_choice.media_file.category == selected.category => {
+            (last_choice.times_chosen + 1, last_choice.block_length)
         }
-    } else {
-        // first time use
-        if let Some(cat) = categories.iter().filter(|cat| cat.visible).choose(&mut rng) {
-            (cat.category.clone(), 1)
-        } else {
-            eprintln!("first time use: cannot choose randomly");
-            return Ok(None);
+        _ => {
+            let block_length = library
+                .category(&selected.category)
+                .map(|category| picker.block_length(category));
+            (1, block_length)
         }
     };
+    let last_choice = LastChoice {
+        media_file: selected.clone(),
+        times_chosen,
+        block_length,
+    };
 
-    eprintln!("search for next song: choose category {}", category_to_use);
-
-    // Pick unplayed file from the chosen category
-    let candidates: Vec<MediaFile> = media_files
-        .clone()
-        .into_iter()
-        .filter(|f| match &current_media_file {
-            Some(current_file) => {
-                f.category == category_to_use && f.played == 0 && f.path != current_file.path
-            }
-            None => f.category == category_to_use && f.played == 0,
-        })
-        .collect();
-
-    eprintln!("search for next song: {} candidates", candidates.len());
-
-    if let Some(selected) = candidates.into_iter().choose(&mut rng) {
-        // Save the new choice
-        let last_choice = LastChoice {
-            media_file: selected.clone(),
-            times_chosen,
-        };
-
-        let serialized = serde_json::to_string_pretty(&last_choice)?;
-        fs::write(last_choice_path, serialized)?;
-
-        eprintln!(
-            "next song is one played not before: {}",
-            selected.path.display()
-        );
-
-        return Ok(Some(selected));
-    }
-
-    // Now already played files ...
-    let candidates: Vec<MediaFile> = media_files.into_iter().collect();
-    eprintln!(
-        "search under already played songs: {} candidates",
-        candidates.len()
-    );
-
-    if let Some(selected) = candidates.into_iter().choose(&mut rng) {
-        // Save the new choice
-        let last_choice = LastChoice {
-            media_file: selected.clone(),
-            times_chosen,
-        };
-
-        let serialized = serde_json::to_string_pretty(&last_choice)?;
-        fs::write(last_choice_path, serialized)?;
+    let serialized = serde_json::to_string_pretty(&last_choice)?;
+    fs::write(last_choice_path, serialized)?;
 
-        eprintln!(
-            "next song was played before since all were played already: {}",
-            selected.path.display()
-        );
-        return Ok(Some(selected));
-    }
-
-    Ok(None)
+    Ok(Some(selected))
 }
 
 pub fn update_play_info(
+    data_dir: &Path,
     media_file: &MediaFile,
     duration: u64,
     category_change: bool,
@@ -238,11 +441,11 @@ pub fn update_play_info(
     let category_name = &media_file.category;
     let file_path = &media_file.path;
 
-    let cat_path = "categories.csv";
-    let media_path = "media-files.csv";
+    let cat_path = data_dir.join(CATEGORIES_CSV);
+    let media_path = data_dir.join(MEDIA_FILES_CSV);
 
-    let cat_file = File::open(cat_path)?;
-    let media_file = File::open(media_path)?;
+    let cat_file = File::open(&cat_path)?;
+    let media_file = File::open(&media_path)?;
 
     let mut rdr_cat = csv::Reader::from_reader(cat_file);
     let mut rdr_media = csv::Reader::from_reader(media_file);
@@ -267,28 +470,113 @@ pub fn update_play_info(
     for media in media_files.iter_mut() {
         if media.category == *category_name && media.path == *file_path {
             media.played += 1;
+            media.last_played = Some(unix_time());
             eprintln!("update CSV: played: {}", media.played);
             break;
         }
     }
 
     // Write updated categories
-    let mut wtr_cat = csv::Writer::from_path(cat_path)?;
-    for cat in &categories {
-        wtr_cat.serialize(cat)?;
-    }
-    wtr_cat.flush()?;
+    write_csv_atomically(&cat_path, &categories)?;
 
     // Write updated media
-    let mut wtr_media = csv::Writer::from_path(media_path)?;
-    for media in &media_files {
-        wtr_media.serialize(media)?;
+    write_csv_atomically(&media_path, &media_files)?;
+
+    Ok(())
+}
+
+pub fn read_media_files(data_dir: &Path) -> Result<Vec<MediaFile>, Box<dyn Error>> {
+    let mut rdr_media = csv::Reader::from_path(data_dir.join(MEDIA_FILES_CSV))?;
+    let media_files = rdr_media.deserialize().collect::<Result<_, _>>()?;
+
+    Ok(media_files)
+}
+
+/// Starts a new play cycle for every visible category whose files were all
+/// played: its epoch goes up and the `played` counters of its files are
+/// reset, so the category offers unplayed files again.
+fn start_new_epochs(data_dir: &Path, library: &mut Library) -> Result<(), Box<dyn Error>> {
+    let mut changed = false;
+
+    for cat in library.categories.iter_mut().filter(|cat| cat.visible) {
+        let mut files = library
+            .media_files
+            .iter_mut()
+            .filter(|f| f.category == cat.category && !f.is_banned())
+            .peekable();
+        if files.peek().is_none() {
+            continue;
+        }
+        let files: Vec<&mut MediaFile> = files.collect();
+        if files.iter().any(|f| f.played == 0) {
+            continue;
+        }
+
+        for f in files {
+            f.played = 0;
+        }
+        cat.epoch += 1;
+        changed = true;
+        eprintln!(
+            "all files of {} were played, start epoch {}",
+            cat.category, cat.epoch
+        );
+    }
+
+    if changed {
+        write_csv_atomically(&data_dir.join(CATEGORIES_CSV), &library.categories)?;
+        write_csv_atomically(&data_dir.join(MEDIA_FILES_CSV), &library.media_files)?;
+    }
+
+    Ok(())
+}
+
+/// Changes the row of one file in `media-files.csv`, e.g. to store what the
+/// analysis found out about it.
+pub fn update_media_file(
+    data_dir: &Path,
+    path: &Path,
+    mut update: impl FnMut(&mut MediaFile),
+) -> Result<(), Box<dyn Error>> {
+    let mut media_files = read_media_files(data_dir)?;
+
+    for media in media_files.iter_mut() {
+        if media.path == path {
+            update(media);
+        }
     }
-    wtr_media.flush()?;
+
+    write_csv_atomically(&data_dir.join(MEDIA_FILES_CSV), &media_files)
+}
+
+/// Writes the rows to a temporary file first and renames it over `path`, so
+/// an interrupted write never leaves a half-written CSV behind.
+pub(crate) fn write_csv_atomically<T: Serialize>(
+    path: &Path,
+    rows: &[T],
+) -> Result<(), Box<dyn Error>> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    let mut wtr = csv::Writer::from_path(&tmp_path)?;
+    for row in rows {
+        wtr.serialize(row)?;
+    }
+    wtr.flush()?;
+    drop(wtr);
+
+    fs::rename(&tmp_path, path)?;
 
     Ok(())
 }
 
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
 fn create_seeded_rng() -> StdRng {
     let now = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -299,3 +587,295 @@ fn create_seeded_rng() -> StdRng {
 
     StdRng::seed_from_u64(seed)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn category(
+        block_min: Option<u32>,
+        block_max: Option<u32>,
+        block_minutes: Option<f64>,
+    ) -> Category {
+        Category {
+            category: "pop".to_string(),
+            duration_overall: 0,
+            current_duration: 0,
+            count: 0,
+            visible: true,
+            block_min,
+            block_max,
+            block_minutes,
+            epoch: 0,
+        }
+    }
+
+    fn media_file(path: &str, category: &str) -> MediaFile {
+        MediaFile {
+            path: PathBuf::from(path),
+            category: category.to_string(),
+            played: 0,
+            cue_in: None,
+            cue_out: None,
+            bpm: None,
+            first_beat: None,
+            key: None,
+            last_played: None,
+            energy: None,
+            artist: None,
+            title: None,
+            analyzed: false,
+            rating: None,
+        }
+    }
+
+    fn data_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mpv-dj-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn banned_files_are_never_chosen() {
+        let dir = data_dir("banned");
+        // pop offers nothing but the file on air, rock only what was played
+        // last
+        let played = MediaFile {
+            played: 1,
+            last_played: Some(unix_time()),
+            ..media_file("rock.mp4", "rock")
+        };
+        let media_files = [
+            media_file("current.mp4", "pop"),
+            media_file("banned-pop.mp4", "pop"),
+            played,
+            media_file("banned-rock.mp4", "rock"),
+        ];
+        write_csv_atomically(&dir.join(MEDIA_FILES_CSV), &media_files).unwrap();
+        write_csv_atomically(
+            &dir.join(CATEGORIES_CSV),
+            &[
+                category(None, None, None),
+                Category {
+                    category: "rock".to_string(),
+                    ..category(None, None, None)
+                },
+            ],
+        )
+        .unwrap();
+        fs::write(
+            dir.join("ratings.csv"),
+            "path,rating\nbanned-pop.mp4,ban\nbanned-rock.mp4,banned\n",
+        )
+        .unwrap();
+
+        let config = SelectionConfig {
+            no_repeat: NoRepeat {
+                hours: Some(1.),
+                tracks: None,
+            },
+            ..Default::default()
+        };
+        let current = media_file("current.mp4", "pop");
+        for strategy in [
+            StrategyKind::CategoryBlocks,
+            StrategyKind::LeastPlayed,
+            StrategyKind::WeightedRandom,
+            StrategyKind::RoundRobin,
+        ] {
+            let config = SelectionConfig {
+                strategy,
+                ..config.clone()
+            };
+            for _ in 0..10 {
+                // In the middle of a block of pop
+                let last_choice = LastChoice {
+                    media_file: current.clone(),
+                    times_chosen: 1,
+                    block_length: None,
+                };
+                fs::write(
+                    dir.join(LAST_CHOICE_JSON),
+                    serde_json::to_string(&last_choice).unwrap(),
+                )
+                .unwrap();
+
+                let chosen = choose_media_file(&dir, Some(current.clone()), &config, None)
+                    .unwrap()
+                    .map(|f| f.path);
+                assert_eq!(chosen, Some(PathBuf::from("rock.mp4")), "{strategy:?}");
+            }
+        }
+
+        // Nothing left but the file on air and banned files
+        write_csv_atomically(&dir.join(MEDIA_FILES_CSV), &media_files[..2]).unwrap();
+        let chosen = choose_media_file(&dir, Some(current), &config, None).unwrap();
+        assert!(chosen.is_none(), "{chosen:?}");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn categories_played_through_start_a_new_epoch() {
+        let dir = data_dir("epochs");
+        let played = |path: &str, category: &str| MediaFile {
+            played: 2,
+            ..media_file(path, category)
+        };
+        let banned = MediaFile {
+            rating: Some(Rating::Ban),
+            ..media_file("pop-banned.mp4", "pop")
+        };
+        let named = |name: &str, visible: bool| Category {
+            category: name.to_string(),
+            visible,
+            ..category(None, None, None)
+        };
+        let mut library = Library {
+            media_files: vec![
+                played("pop-1.mp4", "pop"),
+                played("pop-2.mp4", "pop"),
+                banned,
+                played("rock-1.mp4", "rock"),
+                media_file("rock-2.mp4", "rock"),
+                played("jazz.mp4", "jazz"),
+            ],
+            categories: vec![
+                named("pop", true),
+                named("rock", true),
+                named("jazz", false),
+                named("empty", true),
+            ],
+        };
+
+        start_new_epochs(&dir, &mut library).unwrap();
+
+        // pop was played through, the banned file does not count; rock has
+        // an unplayed file left, jazz is hidden and empty has no files
+        let epochs: Vec<u32> = library.categories.iter().map(|cat| cat.epoch).collect();
+        assert_eq!(epochs, [1, 0, 0, 0]);
+        let played: Vec<u32> = library.media_files.iter().map(|f| f.played).collect();
+        assert_eq!(played, [0, 0, 0, 2, 0, 2]);
+
+        // Persisted for the next pick
+        let mut rdr = csv::Reader::from_path(dir.join(CATEGORIES_CSV)).unwrap();
+        let categories: Vec<Category> = rdr.deserialize().collect::<Result<_, _>>().unwrap();
+        assert_eq!(categories[0].epoch, 1);
+        let media_files = read_media_files(&dir).unwrap();
+        assert_eq!(media_files[0].played, 0);
+
+        // Nothing is played through any more
+        start_new_epochs(&dir, &mut library).unwrap();
+        assert_eq!(library.categories[0].epoch, 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn artist_and_title_come_from_the_file_name() {
+        let split = |path: &str| artist_and_title(Path::new(path));
+        let pair = |artist: &str, title: &str| Some((artist.to_string(), title.to_string()));
+
+        assert_eq!(
+            split("music/pop/Abba - Waterloo.mp4"),
+            pair("Abba", "Waterloo")
+        );
+        // The title may contain the separator, the artist may not
+        assert_eq!(
+            split("AC-DC - Live - Thunderstruck.mp4"),
+            pair("AC-DC", "Live - Thunderstruck")
+        );
+        assert_eq!(split("Abba  -  Waterloo .mp4"), pair("Abba", "Waterloo"));
+
+        for path in [
+            "Waterloo.mp4",
+            "Abba-Waterloo.mp4",
+            " - Waterloo.mp4",
+            "Abba - .mp4",
+            "  -  .mp4",
+        ] {
+            assert_eq!(split(path), None, "{path:?}");
+        }
+    }
+
+    #[test]
+    fn block_range_fills_in_what_is_not_set() {
+        let cases = [
+            // min, max, minutes => range
+            ((None, None, None), (4, 4)),
+            ((Some(2), None, None), (2, 2)),
+            ((None, Some(6), None), (6, 6)),
+            ((Some(2), Some(6), None), (2, 6)),
+            ((Some(0), None, None), (1, 1)),
+            ((Some(5), Some(3), None), (5, 5)),
+            ((None, None, Some(10.)), (1, u32::MAX)),
+            ((Some(2), None, Some(10.)), (2, u32::MAX)),
+            ((None, Some(6), Some(10.)), (1, 6)),
+        ];
+
+        for ((min, max, minutes), range) in cases {
+            assert_eq!(
+                category(min, max, minutes).block_range(),
+                range,
+                "min {min:?}, max {max:?}, minutes {minutes:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn block_in_tracks_lasts_the_drawn_length() {
+        let cases = [
+            // min, max, drawn length, tracks so far => over
+            ((None, None), None, 3, false),
+            ((None, None), None, 4, true),
+            ((Some(2), Some(6)), Some(3), 2, false),
+            ((Some(2), Some(6)), Some(3), 3, true),
+            ((Some(2), Some(6)), None, 5, false),
+            ((Some(2), Some(6)), None, 6, true),
+            // A drawn length outside of the range, e.g. after the range was
+            // changed in the middle of the block
+            ((Some(2), Some(6)), Some(10), 6, true),
+            ((Some(2), Some(6)), Some(1), 1, false),
+            ((Some(2), Some(6)), Some(1), 2, true),
+        ];
+
+        for ((min, max), block_length, times_chosen, over) in cases {
+            assert_eq!(
+                category(min, max, None).block_over(times_chosen, block_length),
+                over,
+                "min {min:?}, max {max:?}, length {block_length:?}, {times_chosen} tracks"
+            );
+        }
+    }
+
+    #[test]
+    fn block_in_minutes_ends_after_the_minutes_or_the_most_tracks() {
+        let cases = [
+            // min, max, seconds played in the block, tracks so far => over
+            ((None, None), 599, 100, false),
+            ((None, None), 600, 1, true),
+            ((Some(2), Some(6)), 599, 3, false),
+            // Not before the fewest tracks, even after the minutes
+            ((Some(2), Some(6)), 900, 1, false),
+            ((Some(2), Some(6)), 600, 2, true),
+            // Not after the most tracks, even before the minutes
+            ((Some(2), Some(6)), 0, 6, true),
+        ];
+
+        for ((min, max), current_duration, times_chosen, over) in cases {
+            let category = Category {
+                current_duration,
+                ..category(min, max, Some(10.))
+            };
+            // The drawn length does not matter for blocks in minutes
+            for block_length in [None, Some(1), Some(100)] {
+                assert_eq!(
+                    category.block_over(times_chosen, block_length),
+                    over,
+                    "min {min:?}, max {max:?}, {current_duration} s, {times_chosen} tracks"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::commands::MpvClient;
+use crate::error::MpvError;
+use crate::transport::Transport;
+
+/// One entry of the `af` or `vf` filter chain.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Filter {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub params: HashMap<String, String>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// Value of `fs-screen`: a screen number or one of mpv's names for it.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum FsScreen {
+    Index(usize),
+    Name(String),
+}
+
+/// One entry of `track-list`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Track {
+    pub id: i64,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub title: Option<String>,
+    pub lang: Option<String>,
+    pub codec: Option<String>,
+    #[serde(default)]
+    pub selected: bool,
+    #[serde(default)]
+    pub external: bool,
+    pub external_filename: Option<String>,
+}
+
+/// Typed wrappers for the properties a DJ needs.
+impl<T: Transport> MpvClient<T> {
+    pub fn get_pause(&mut self) -> Result<bool, MpvError> {
+        self.get_property("pause")
+    }
+
+    pub fn set_pause(&mut self, pause: bool) -> Result<(), MpvError> {
+        self.set_property("pause", pause)
+    }
+
+    pub fn get_speed(&mut self) -> Result<f64, MpvError> {
+        self.get_property("speed")
+    }
+
+    pub fn set_speed(&mut self, speed: f64) -> Result<(), MpvError> {
+        self.set_property("speed", speed)
+    }
+
+    pub fn get_volume(&mut self) -> Result<f64, MpvError> {
+        self.get_property("volume")
+    }
+
+    pub fn set_volume(&mut self, volume: f64) -> Result<(), MpvError> {
+        self.set_property("volume", volume)
+    }
+
+    pub fn get_mute(&mut self) -> Result<bool, MpvError> {
+        self.get_property("mute")
+    }
+
+    pub fn set_mute(&mut self, mute: bool) -> Result<(), MpvError> {
+        self.set_property("mute", mute)
+    }
+
+    pub fn get_audio_device(&mut self) -> Result<String, MpvError> {
+        self.get_property("audio-device")
+    }
+
+    pub fn set_audio_device(&mut self, device: &str) -> Result<(), MpvError> {
+        self.set_property("audio-device", device)
+    }
+
+    pub fn get_fs_screen(&mut self) -> Result<FsScreen, MpvError> {
+        self.get_property("fs-screen")
+    }
+
+    pub fn set_fs_screen(&mut self, screen: usize) -> Result<(), MpvError> {
+        self.set_property("fs-screen", screen)
+    }
+
+    /// The audio filter chain (`af`).
+    pub fn get_audio_filters(&mut self) -> Result<Vec<Filter>, MpvError> {
+        self.get_property("af")
+    }
+
+    pub fn set_audio_filters(&mut self, filters: &[Filter]) -> Result<(), MpvError> {
+        self.set_property("af", filters)
+    }
+
+    /// The video filter chain (`vf`).
+    pub fn get_video_filters(&mut self) -> Result<Vec<Filter>, MpvError> {
+        self.get_property("vf")
+    }
+
+    pub fn set_video_filters(&mut self, filters: &[Filter]) -> Result<(), MpvError> {
+        self.set_property("vf", filters)
+    }
+
+    /// External subtitle files loaded with every following file (`sub-file`).
+    pub fn get_sub_files(&mut self) -> Result<Vec<String>, MpvError> {
+        self.get_property("sub-files")
+    }
+
+    pub fn set_sub_files(&mut self, files: &[String]) -> Result<(), MpvError> {
+        self.set_property("sub-files", files)
+    }
+
+    /// Adds an external subtitle file to the file that is playing right now.
+    pub fn add_sub_file(&mut self, path: &Path) -> Result<(), MpvError> {
+        self.command(json!(["sub-add", path]))?;
+        Ok(())
+    }
+
+    pub fn get_chapter(&mut self) -> Result<i64, MpvError> {
+        self.get_property("chapter")
+    }
+
+    pub fn set_chapter(&mut self, chapter: i64) -> Result<(), MpvError> {
+        self.set_property("chapter", chapter)
+    }
+
+    /// Container tags of the current file, e.g. `artist` and `title`.
+    pub fn get_metadata(&mut self) -> Result<HashMap<String, String>, MpvError> {
+        self.get_property("metadata")
+    }
+
+    pub fn get_track_list(&mut self) -> Result<Vec<Track>, MpvError> {
+        self.get_property("track-list")
+    }
+}
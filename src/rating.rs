@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::media_files::MediaFile;
+
+/// Where ratings are kept, next to `media-files.csv`. A file of its own, so
+/// they survive a rescan of the library.
+const RATINGS_CSV: &str = "ratings.csv";
+
+/// What the DJ thinks of a track, written `ban`, `1` to `5` or `favourite`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Rating {
+    /// Never played.
+    Ban,
+    /// One to five stars.
+    Stars(u8),
+    Favourite,
+}
+
+impl Rating {
+    /// How much more likely the track is picked than an unrated one.
+    pub fn weight(self) -> f64 {
+        match self {
+            Rating::Ban => 0.,
+            Rating::Stars(1) => 0.25,
+            Rating::Stars(2) => 0.5,
+            Rating::Stars(3) => 1.,
+            Rating::Stars(4) => 1.5,
+            Rating::Stars(_) => 2.,
+            Rating::Favourite => 3.,
+        }
+    }
+}
+
+impl fmt::Display for Rating {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rating::Ban => write!(f, "ban"),
+            Rating::Stars(stars) => write!(f, "{stars}"),
+            Rating::Favourite => write!(f, "favourite"),
+        }
+    }
+}
+
+impl TryFrom<String> for Rating {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.trim().to_lowercase().as_str() {
+            "ban" | "banned" => Ok(Rating::Ban),
+            "favourite" | "favorite" => Ok(Rating::Favourite),
+            stars => match stars.parse() {
+                Ok(stars @ 1..=5) => Ok(Rating::Stars(stars)),
+                _ => Err(format!("invalid rating: {value}")),
+            },
+        }
+    }
+}
+
+impl From<Rating> for String {
+    fn from(rating: Rating) -> Self {
+        rating.to_string()
+    }
+}
+
+/// One row of `ratings.csv`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct RatedFile {
+    path: PathBuf,
+    rating: Rating,
+}
+
+/// Every rating so far, empty if nothing was rated yet. Rows that cannot be
+/// read are skipped, so a typo does not stop the set.
+fn read_ratings(data_dir: &Path) -> Result<Vec<RatedFile>, Box<dyn Error>> {
+    let path = data_dir.join(RATINGS_CSV);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut rdr = csv::Reader::from_path(path)?;
+    let ratings = rdr
+        .deserialize()
+        .filter_map(|row| {
+            row.inspect_err(|e| eprintln!("Skip a row of {RATINGS_CSV}: {e}"))
+                .ok()
+        })
+        .collect();
+
+    Ok(ratings)
+}
+
+/// Sets the rating of every file that has one in `ratings.csv`.
+pub fn apply(data_dir: &Path, media_files: &mut [MediaFile]) -> Result<(), Box<dyn Error>> {
+    let ratings: HashMap<PathBuf, Rating> = read_ratings(data_dir)?
+        .into_iter()
+        .map(|rated| (rated.path, rated.rating))
+        .collect();
+
+    for media in media_files.iter_mut() {
+        media.rating = ratings.get(&media.path).copied();
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rating(value: &str) -> Result<Rating, String> {
+        Rating::try_from(value.to_string())
+    }
+
+    #[test]
+    fn ratings_are_read_leniently() {
+        assert_eq!(rating("ban"), Ok(Rating::Ban));
+        assert_eq!(rating("Banned"), Ok(Rating::Ban));
+        assert_eq!(rating("favourite"), Ok(Rating::Favourite));
+        assert_eq!(rating("FAVORITE"), Ok(Rating::Favourite));
+        assert_eq!(rating(" 3 "), Ok(Rating::Stars(3)));
+        assert_eq!(rating("1"), Ok(Rating::Stars(1)));
+        assert_eq!(rating("5"), Ok(Rating::Stars(5)));
+
+        for invalid in ["0", "6", "-1", "3.5", "", "love"] {
+            assert!(rating(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn ratings_are_written_as_they_are_read() {
+        for value in ["ban", "1", "5", "favourite"] {
+            assert_eq!(String::from(rating(value).unwrap()), value);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::{DateTime, Datelike, Duration, Local, Timelike, Weekday};
+use serde::{Deserialize, Serialize};
+
+use crate::selection::Library;
+
+/// A wall-clock time of day, written `HH:MM`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ClockTime {
+    /// Minutes since midnight.
+    pub minutes: u32,
+}
+
+impl fmt::Display for ClockTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.minutes / 60, self.minutes % 60)
+    }
+}
+
+impl TryFrom<String> for ClockTime {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let value = value.trim();
+        let invalid = || format!("invalid time of day: {value}");
+
+        let (hours, minutes) = value.split_once(':').ok_or_else(invalid)?;
+        // 7:5 is more likely a typo of 7:50 than meant as 7:05
+        if minutes.len() != 2 {
+            return Err(invalid());
+        }
+        let hours: u32 = hours.parse().map_err(|_| invalid())?;
+        let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+        // 24:00 is the end of the day
+        if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+            return Err(invalid());
+        }
+
+        Ok(ClockTime {
+            minutes: hours * 60 + minutes,
+        })
+    }
+}
+
+impl From<ClockTime> for String {
+    fn from(time: ClockTime) -> Self {
+        time.to_string()
+    }
+}
+
+/// One entry of the schedule: while it is in effect, its categories are
+/// shown, hidden or reweighted. E.g. no metal on Sunday brunch:
+/// `{"categories": ["metal"], "days": ["sun"], "from": "10:00", "to": "14:00", "visible": false}`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ScheduleRule {
+    pub categories: Vec<String>,
+    /// Days the rule starts on, every day if empty. A rule that runs past
+    /// midnight counts for the day it started on.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    /// Start of the rule, midnight if missing.
+    #[serde(default)]
+    pub from: Option<ClockTime>,
+    /// End of the rule, midnight if missing. An end before the start runs
+    /// past midnight.
+    #[serde(default)]
+    pub to: Option<ClockTime>,
+    /// Overrides the `visible` column of `categories.csv`.
+    #[serde(default)]
+    pub visible: Option<bool>,
+    /// Multiplies the chance of the categories to be chosen.
+    #[serde(default)]
+    pub weight: Option<f64>,
+}
+
+impl ScheduleRule {
+    /// Whether the rule is in effect at `now`.
+    pub fn is_active(&self, now: DateTime<Local>) -> bool {
+        let minute = now.hour() * 60 + now.minute();
+        let from = self.from.map_or(0, |from| from.minutes);
+        let to = self.to.map_or(24 * 60, |to| to.minutes);
+        let on_day = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+
+        if from < to {
+            on_day(now.weekday()) && (from..to).contains(&minute)
+        } else {
+            // Past midnight, the rule belongs to the day before
+            let yesterday = (now - Duration::days(1)).weekday();
+            (on_day(now.weekday()) && minute >= from) || (on_day(yesterday) && minute < to)
+        }
+    }
+}
+
+/// Rules that change the categories by time of day and weekday, from
+/// `config.json`. Later rules win over earlier ones, weights multiply.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Schedule {
+    pub rules: Vec<ScheduleRule>,
+}
+
+impl Schedule {
+    /// Shows and hides the categories of `library` as the schedule says for
+    /// `now` and returns the weights of the reweighted categories.
+    pub fn apply(&self, library: &mut Library, now: DateTime<Local>) -> HashMap<String, f64> {
+        let mut weights = HashMap::new();
+
+        for rule in self.rules.iter().filter(|rule| rule.is_active(now)) {
+            for name in &rule.categories {
+                let Some(category) = library
+                    .categories
+                    .iter_mut()
+                    .find(|cat| cat.category == *name)
+                else {
+                    eprintln!("schedule: unknown category {name}");
+                    continue;
+                };
+
+                if let Some(visible) = rule.visible
+                    && category.visible != visible
+                {
+                    eprintln!(
+                        "schedule: {name} is {} now",
+                        if visible { "shown" } else { "hidden" }
+                    );
+                    category.visible = visible;
+                }
+                if let Some(weight) = rule.weight {
+                    *weights.entry(name.clone()).or_insert(1.) *= weight.max(0.);
+                }
+            }
+        }
+
+        for (name, weight) in &weights {
+            eprintln!("schedule: weight of {name} is {weight}");
+        }
+
+        weights
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::media_files::Category;
+
+    /// 16 October 2026 is a Friday.
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2026, 10, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn time(value: &str) -> Result<ClockTime, String> {
+        ClockTime::try_from(value.to_string())
+    }
+
+    fn rule(categories: &[&str], from: Option<&str>, to: Option<&str>) -> ScheduleRule {
+        ScheduleRule {
+            categories: categories.iter().map(|name| name.to_string()).collect(),
+            days: vec![],
+            from: from.map(|from| time(from).unwrap()),
+            to: to.map(|to| time(to).unwrap()),
+            visible: None,
+            weight: None,
+        }
+    }
+
+    #[test]
+    fn clock_times_are_hours_and_minutes() {
+        assert_eq!(time("00:00"), Ok(ClockTime { minutes: 0 }));
+        assert_eq!(time(" 7:05 "), Ok(ClockTime { minutes: 425 }));
+        assert_eq!(time("24:00"), Ok(ClockTime { minutes: 1440 }));
+        assert_eq!(time("22:30").unwrap().to_string(), "22:30");
+
+        for invalid in ["24:01", "25:00", "12:60", "7:5", "7:005", "7", "", "ab:cd"] {
+            assert!(time(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn rule_past_midnight_belongs_to_the_day_it_starts() {
+        let party = ScheduleRule {
+            days: vec![Weekday::Fri],
+            ..rule(&["party"], Some("22:00"), Some("02:00"))
+        };
+
+        assert_eq!(at(16, 0, 0).weekday(), Weekday::Fri);
+        // Friday night into Saturday morning
+        assert!(party.is_active(at(16, 22, 0)));
+        assert!(party.is_active(at(16, 23, 59)));
+        assert!(party.is_active(at(17, 0, 0)));
+        assert!(party.is_active(at(17, 1, 59)));
+
+        assert!(!party.is_active(at(16, 21, 59)));
+        assert!(!party.is_active(at(17, 2, 0)));
+        // The early hours of Friday belong to Thursday night
+        assert!(!party.is_active(at(16, 1, 0)));
+        // Saturday night is no party
+        assert!(!party.is_active(at(17, 23, 0)));
+    }
+
+    #[test]
+    fn rule_within_a_day() {
+        let brunch = ScheduleRule {
+            days: vec![Weekday::Sun],
+            ..rule(&["metal"], Some("10:00"), Some("14:00"))
+        };
+        assert!(brunch.is_active(at(18, 10, 0)));
+        assert!(!brunch.is_active(at(18, 14, 0)));
+        assert!(!brunch.is_active(at(17, 12, 0)));
+
+        // Without times all day, without days every day
+        let always = rule(&["pop"], None, None);
+        assert!(always.is_active(at(16, 0, 0)));
+        assert!(always.is_active(at(17, 23, 59)));
+    }
+
+    #[test]
+    fn weights_of_active_rules_multiply() {
+        let category = |name: &str| Category {
+            category: name.to_string(),
+            duration_overall: 0,
+            current_duration: 0,
+            count: 0,
+            visible: true,
+            block_min: None,
+            block_max: None,
+            block_minutes: None,
+            epoch: 0,
+        };
+        let mut library = Library {
+            media_files: vec![],
+            categories: vec![category("pop"), category("rock"), category("jazz")],
+        };
+        let schedule = Schedule {
+            rules: vec![
+                ScheduleRule {
+                    weight: Some(2.),
+                    ..rule(&["pop", "rock"], None, None)
+                },
+                ScheduleRule {
+                    weight: Some(3.),
+                    visible: Some(false),
+                    ..rule(&["pop"], Some("20:00"), None)
+                },
+                // Not in effect
+                ScheduleRule {
+                    weight: Some(10.),
+                    ..rule(&["pop"], Some("08:00"), Some("09:00"))
+                },
+                ScheduleRule {
+                    weight: Some(-1.),
+                    ..rule(&["jazz", "unknown"], None, None)
+                },
+                // Later rules win
+                ScheduleRule {
+                    visible: Some(true),
+                    ..rule(&["pop"], Some("21:00"), Some("22:00"))
+                },
+            ],
+        };
+
+        let weights = schedule.apply(&mut library, at(16, 21, 30));
+
+        assert_eq!(
+            weights,
+            HashMap::from([
+                ("pop".to_string(), 6.),
+                ("rock".to_string(), 2.),
+                ("jazz".to_string(), 0.),
+            ])
+        );
+        assert!(library.categories.iter().all(|cat| cat.visible));
+
+        let weights = schedule.apply(&mut library, at(16, 20, 30));
+        assert_eq!(weights["pop"], 6.);
+        assert!(!library.categories[0].visible);
+    }
+}
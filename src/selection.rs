@@ -0,0 +1,1070 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use rand::Rng;
+use rand::rngs::StdRng;
+use rand::seq::IndexedRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::media_files::{Category, LastChoice, MediaFile, NoRepeat, SelectionConfig};
+
+/// Everything that can be played: the rows of `media-files.csv` and
+/// `categories.csv`.
+#[derive(Debug, Clone)]
+pub struct Library {
+    pub media_files: Vec<MediaFile>,
+    pub categories: Vec<Category>,
+}
+
+impl Library {
+    pub fn visible_categories(&self) -> impl Iterator<Item = &Category> {
+        self.categories.iter().filter(|cat| cat.visible)
+    }
+
+    pub fn category(&self, category: &str) -> Option<&Category> {
+        self.categories.iter().find(|cat| cat.category == category)
+    }
+
+    pub fn is_visible(&self, category: &str) -> bool {
+        self.visible_categories()
+            .any(|cat| cat.category == category)
+    }
+
+    /// The library without the files that were played too recently to be
+    /// played again, `now` being seconds since the Unix epoch. `current` is
+    /// on air and counts as the last track, although it is only reported as
+    /// played once it is over.
+    pub fn without_recently_played(
+        &self,
+        no_repeat: &NoRepeat,
+        now: u64,
+        current: Option<&MediaFile>,
+    ) -> Library {
+        let mut played: Vec<u64> = self
+            .media_files
+            .iter()
+            .filter(|f| !is_current(f, current))
+            .filter_map(|f| f.last_played)
+            .collect();
+        played.sort_unstable_by(|a, b| b.cmp(a));
+
+        // Files played at or after this time are among the last tracks
+        let reported = no_repeat
+            .tracks
+            .map(|tracks| tracks.saturating_sub(usize::from(current.is_some())));
+        let since_tracks = reported
+            .filter(|&tracks| tracks > 0)
+            .and_then(|tracks| played.get(tracks - 1).or(played.last()).copied());
+        let since_hours = no_repeat
+            .hours
+            .map(|hours| now.saturating_sub((hours * 3600.) as u64));
+        let since = since_tracks.into_iter().chain(since_hours).min();
+
+        let media_files: Vec<MediaFile> = self
+            .media_files
+            .iter()
+            .filter(|f| match (f.last_played, since) {
+                (Some(last_played), Some(since)) => last_played < since,
+                _ => true,
+            })
+            .cloned()
+            .collect();
+        if media_files.len() < self.media_files.len() {
+            eprintln!(
+                "no-repeat window: {} files were played too recently",
+                self.media_files.len() - media_files.len()
+            );
+        }
+
+        Library {
+            media_files,
+            categories: self.categories.clone(),
+        }
+    }
+
+    /// The library without the files by an artist of the last `tracks`
+    /// files, `current` being the last one.
+    pub fn without_recent_artists(&self, tracks: usize, current: Option<&MediaFile>) -> Library {
+        if tracks == 0 {
+            return self.clone();
+        }
+
+        let mut played: Vec<&MediaFile> = self
+            .media_files
+            .iter()
+            .filter(|f| f.last_played.is_some() && !is_current(f, current))
+            .collect();
+        played.sort_unstable_by_key(|f| Reverse(f.last_played));
+        let recent: Vec<&MediaFile> = current
+            .into_iter()
+            .chain(played)
+            .take(tracks)
+            .filter(|f| f.artist.is_some())
+            .collect();
+
+        let media_files: Vec<MediaFile> = self
+            .media_files
+            .iter()
+            .filter(|f| !recent.iter().any(|recent| recent.same_artist(f)))
+            .cloned()
+            .collect();
+        if media_files.len() < self.media_files.len() {
+            eprintln!(
+                "artist separation: {} files are by an artist of the last {tracks} tracks",
+                self.media_files.len() - media_files.len()
+            );
+        }
+
+        Library {
+            media_files,
+            categories: self.categories.clone(),
+        }
+    }
+}
+
+/// What was played before, as far as selection remembers it.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    pub last_choice: Option<LastChoice>,
+}
+
+/// A policy for the next file to play.
+pub trait SelectionStrategy {
+    /// The file to play after `current`, `None` if there is nothing to play.
+    fn choose(
+        &self,
+        library: &Library,
+        history: &History,
+        current: Option<&MediaFile>,
+        picker: &mut Picker,
+    ) -> Option<MediaFile>;
+}
+
+/// The strategies that can be chosen in `config.json`.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StrategyKind {
+    /// A block of tracks per category, unplayed files first.
+    #[default]
+    CategoryBlocks,
+    /// The files played least often.
+    LeastPlayed,
+    /// Any file, files played less often being more likely.
+    WeightedRandom,
+    /// One track per category, the categories in turn.
+    RoundRobin,
+}
+
+impl StrategyKind {
+    pub fn strategy(self) -> Box<dyn SelectionStrategy> {
+        match self {
+            StrategyKind::CategoryBlocks => Box::new(CategoryBlocks),
+            StrategyKind::LeastPlayed => Box::new(LeastPlayed),
+            StrategyKind::WeightedRandom => Box::new(WeightedRandom),
+            StrategyKind::RoundRobin => Box::new(RoundRobin),
+        }
+    }
+}
+
+/// How far a category's weight may move away from its airtime target, in
+/// either direction.
+const MAX_AIRTIME_CORRECTION: f64 = 10.;
+
+/// Draws one file or category out of the candidates a strategy found.
+/// Preferences that apply to every strategy live here, e.g. harmonic mixing,
+/// airtime targets and the weights of the schedule.
+pub struct Picker {
+    rng: StdRng,
+    config: SelectionConfig,
+    category_weights: HashMap<String, f64>,
+    target_energy: Option<f64>,
+}
+
+impl Picker {
+    pub fn new(rng: StdRng, config: &SelectionConfig) -> Self {
+        Self {
+            rng,
+            config: config.clone(),
+            category_weights: HashMap::new(),
+            target_energy: None,
+        }
+    }
+
+    /// Makes files and categories more or less likely by category, 1 being
+    /// unchanged.
+    pub fn with_category_weights(mut self, category_weights: HashMap<String, f64>) -> Self {
+        self.category_weights = category_weights;
+        self
+    }
+
+    /// Makes files the more likely the closer their energy is to `target`,
+    /// see [`SetPlan::closeness`](crate::energy::SetPlan::closeness).
+    pub fn with_target_energy(mut self, target_energy: Option<f64>) -> Self {
+        self.target_energy = target_energy;
+        self
+    }
+
+    fn category_weight(&self, category: &str) -> f64 {
+        self.category_weights.get(category).copied().unwrap_or(1.)
+    }
+
+    /// Any one of the candidates, all equally likely.
+    pub fn pick(
+        &mut self,
+        candidates: Vec<&MediaFile>,
+        current: Option<&MediaFile>,
+    ) -> Option<MediaFile> {
+        self.pick_weighted(candidates, current, |_| 1.)
+    }
+
+    /// One of the candidates with a probability proportional to `weight`,
+    /// the weight of its category, its rating and its closeness to the target
+    /// energy.
+    pub fn pick_weighted(
+        &mut self,
+        candidates: Vec<&MediaFile>,
+        current: Option<&MediaFile>,
+        weight: impl Fn(&MediaFile) -> f64,
+    ) -> Option<MediaFile> {
+        let candidates = self.prefer_harmonic(candidates, current);
+        let weights: Vec<f64> = candidates
+            .iter()
+            .map(|f| {
+                let rating = f.rating.map_or(1., |rating| rating.weight());
+                weight(f) * self.category_weight(&f.category) * self.energy_weight(f) * rating
+            })
+            .collect();
+        let weighted: Vec<(&MediaFile, f64)> = candidates.into_iter().zip(weights).collect();
+
+        weighted
+            .choose_weighted(&mut self.rng, |(_, weight)| *weight)
+            .ok()
+            .map(|(f, _)| (*f).clone())
+    }
+
+    /// One of the categories. Without airtime targets all are equally
+    /// likely; with them a category is the more likely the further its share
+    /// of `duration_overall` is behind its target share. Categories without
+    /// a target are only chosen if none of the others has one. Either way
+    /// the chance is scaled by the weight of the category.
+    pub fn pick_category<'a>(
+        &mut self,
+        categories: impl Iterator<Item = &'a Category>,
+    ) -> Option<&'a Category> {
+        let categories: Vec<&Category> = categories.collect();
+
+        let target = |cat: &Category| {
+            self.config
+                .airtime
+                .get(&cat.category)
+                .copied()
+                .unwrap_or(0.)
+        };
+        let total_target: f64 = categories.iter().map(|cat| target(cat)).sum();
+        let total_airtime: f64 = categories
+            .iter()
+            .map(|cat| cat.duration_overall as f64)
+            .sum();
+
+        let airtime_weight = |cat: &Category| -> f64 {
+            if total_target <= 0. {
+                return 1.;
+            }
+            let target_share = target(cat) / total_target;
+            if total_airtime <= 0. {
+                return target_share;
+            }
+            let share = cat.duration_overall as f64 / total_airtime;
+            let correction = if share > 0. {
+                (target_share / share).clamp(1. / MAX_AIRTIME_CORRECTION, MAX_AIRTIME_CORRECTION)
+            } else {
+                MAX_AIRTIME_CORRECTION
+            };
+            eprintln!(
+                "airtime of {}: {:.0}% for a target of {:.0}%",
+                cat.category,
+                share * 100.,
+                target_share * 100.
+            );
+            target_share * correction
+        };
+
+        let weighted: Vec<(&Category, f64)> = categories
+            .iter()
+            .map(|&cat| {
+                (
+                    cat,
+                    airtime_weight(cat) * self.category_weight(&cat.category),
+                )
+            })
+            .collect();
+
+        weighted
+            .choose_weighted(&mut self.rng, |(_, weight)| *weight)
+            .ok()
+            .map(|(cat, _)| *cat)
+    }
+
+    fn energy_weight(&self, media_file: &MediaFile) -> f64 {
+        self.target_energy.map_or(1., |target| {
+            self.config.set_plan.closeness(target, media_file.energy)
+        })
+    }
+
+    /// A block length for the category, anywhere between its fewest and
+    /// most tracks in a row.
+    pub fn block_length(&mut self, category: &Category) -> u32 {
+        let (min, max) = category.block_range();
+        self.rng.random_range(min..=max)
+    }
+
+    /// In harmonic mode, narrows the candidates down to those whose key is
+    /// compatible with the current file. Without such a candidate, or
+    /// without a known key of the current file, all candidates stay.
+    fn prefer_harmonic<'a>(
+        &self,
+        candidates: Vec<&'a MediaFile>,
+        current: Option<&MediaFile>,
+    ) -> Vec<&'a MediaFile> {
+        let Some(current_key) = current.and_then(|current| current.key) else {
+            return candidates;
+        };
+        if !self.config.harmonic {
+            return candidates;
+        }
+
+        let compatible: Vec<&MediaFile> = candidates
+            .iter()
+            .copied()
+            .filter(|f| f.key.is_some_and(|key| key.is_compatible(current_key)))
+            .collect();
+
+        if compatible.is_empty() {
+            eprintln!("no candidate in a key compatible with {current_key}, choose any");
+            return candidates;
+        }
+
+        eprintln!(
+            "search for next song: {} candidates compatible with {current_key}",
+            compatible.len()
+        );
+        compatible
+    }
+}
+
+fn is_current(f: &MediaFile, current: Option<&MediaFile>) -> bool {
+    current.is_some_and(|current| current.path == f.path)
+}
+
+fn is_block_over(library: &Library, last_choice: &LastChoice) -> bool {
+    let category = &last_choice.media_file.category;
+    let over = match library.category(category) {
+        // Hidden by the schedule in the middle of the block
+        Some(category) if !category.visible => true,
+        Some(category) => category.block_over(last_choice.times_chosen, last_choice.block_length),
+        None => true,
+    };
+    if over {
+        eprintln!(
+            "block of {category} is over after {} tracks",
+            last_choice.times_chosen
+        );
+    }
+
+    over
+}
+
+/// A block of tracks of one category, as long as `categories.csv` says, then
+/// a random other visible category. Within the category a random unplayed
+/// file is chosen, or a played one if the only unplayed file is on air. A
+/// category without any other file yields nothing.
+pub struct CategoryBlocks;
+
+impl SelectionStrategy for CategoryBlocks {
+    fn choose(
+        &self,
+        library: &Library,
+        history: &History,
+        current: Option<&MediaFile>,
+        picker: &mut Picker,
+    ) -> Option<MediaFile> {
+        let category_to_use = match &history.last_choice {
+            Some(last_choice) if is_block_over(library, last_choice) => {
+                // pick a different category
+                let other_categories = library
+                    .visible_categories()
+                    .filter(|cat| cat.category != last_choice.media_file.category);
+
+                match picker.pick_category(other_categories) {
+                    Some(new_cat) => new_cat.category.clone(),
+                    None => {
+                        eprintln!("no other category available, so take the last one anyway");
+                        last_choice.media_file.category.clone()
+                    }
+                }
+            }
+            Some(last_choice) => last_choice.media_file.category.clone(),
+            None => {
+                // first time use
+                match picker.pick_category(library.visible_categories()) {
+                    Some(cat) => cat.category.clone(),
+                    None => {
+                        eprintln!("first time use: cannot choose randomly");
+                        return None;
+                    }
+                }
+            }
+        };
+
+        eprintln!("search for next song: choose category {}", category_to_use);
+
+        // Pick unplayed file from the chosen category
+        let candidates: Vec<&MediaFile> = library
+            .media_files
+            .iter()
+            .filter(|f| f.category == category_to_use && f.played == 0 && !is_current(f, current))
+            .collect();
+
+        eprintln!("search for next song: {} candidates", candidates.len());
+
+        if let Some(selected) = picker.pick(candidates, current) {
+            eprintln!(
+                "next song is one played not before: {}",
+                selected.path.display()
+            );
+            return Some(selected);
+        }
+
+        // Now already played files of the category ...
+        let candidates: Vec<&MediaFile> = library
+            .media_files
+            .iter()
+            .filter(|f| f.category == category_to_use && !is_current(f, current))
+            .collect();
+        eprintln!(
+            "search under already played songs: {} candidates",
+            candidates.len()
+        );
+
+        if let Some(selected) = picker.pick(candidates, current) {
+            eprintln!(
+                "next song was played before since all were played already: {}",
+                selected.path.display()
+            );
+            return Some(selected);
+        }
+
+        eprintln!("nothing else to play in {category_to_use}");
+        None
+    }
+}
+
+/// A random file among those of the visible categories that were played
+/// least often.
+pub struct LeastPlayed;
+
+impl SelectionStrategy for LeastPlayed {
+    fn choose(
+        &self,
+        library: &Library,
+        _history: &History,
+        current: Option<&MediaFile>,
+        picker: &mut Picker,
+    ) -> Option<MediaFile> {
+        let playable: Vec<&MediaFile> = library
+            .media_files
+            .iter()
+            .filter(|f| library.is_visible(&f.category) && !is_current(f, current))
+            .collect();
+
+        let least = playable.iter().map(|f| f.played).min()?;
+        let candidates: Vec<&MediaFile> =
+            playable.into_iter().filter(|f| f.played == least).collect();
+        eprintln!(
+            "search for next song: {} candidates played {least} times",
+            candidates.len()
+        );
+
+        picker.pick(candidates, current)
+    }
+}
+
+/// Any file of the visible categories, with a chance inversely proportional
+/// to how often it was played.
+pub struct WeightedRandom;
+
+impl SelectionStrategy for WeightedRandom {
+    fn choose(
+        &self,
+        library: &Library,
+        _history: &History,
+        current: Option<&MediaFile>,
+        picker: &mut Picker,
+    ) -> Option<MediaFile> {
+        let candidates: Vec<&MediaFile> = library
+            .media_files
+            .iter()
+            .filter(|f| library.is_visible(&f.category) && !is_current(f, current))
+            .collect();
+        eprintln!("search for next song: {} candidates", candidates.len());
+
+        picker.pick_weighted(candidates, current, |f| 1. / (1. + f64::from(f.played)))
+    }
+}
+
+/// The visible categories in the order of `categories.csv`, one track each.
+/// Within a category the least played files come first.
+pub struct RoundRobin;
+
+impl SelectionStrategy for RoundRobin {
+    fn choose(
+        &self,
+        library: &Library,
+        history: &History,
+        current: Option<&MediaFile>,
+        picker: &mut Picker,
+    ) -> Option<MediaFile> {
+        let categories: Vec<&Category> = library.visible_categories().collect();
+        let last = history.last_choice.as_ref().and_then(|last_choice| {
+            categories
+                .iter()
+                .position(|cat| cat.category == last_choice.media_file.category)
+        });
+        let start = last.map_or(0, |last| last + 1);
+
+        // The next category in turn that has anything to play
+        for category in categories.iter().cycle().skip(start).take(categories.len()) {
+            let in_category: Vec<&MediaFile> = library
+                .media_files
+                .iter()
+                .filter(|f| f.category == category.category && !is_current(f, current))
+                .collect();
+            let Some(least) = in_category.iter().map(|f| f.played).min() else {
+                continue;
+            };
+
+            eprintln!(
+                "search for next song: category {} is next",
+                category.category
+            );
+            let candidates = in_category
+                .into_iter()
+                .filter(|f| f.played == least)
+                .collect();
+            return picker.pick(candidates, current);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn media_file(path: &str, category: &str, played: u32) -> MediaFile {
+        MediaFile {
+            path: PathBuf::from(path),
+            category: category.to_string(),
+            played,
+            cue_in: None,
+            cue_out: None,
+            bpm: None,
+            first_beat: None,
+            key: None,
+            last_played: None,
+            energy: None,
+            artist: None,
+            title: None,
+            analyzed: false,
+            rating: None,
+        }
+    }
+
+    fn category(name: &str, visible: bool) -> Category {
+        Category {
+            category: name.to_string(),
+            duration_overall: 0,
+            current_duration: 0,
+            count: 0,
+            visible,
+            block_min: None,
+            block_max: None,
+            block_minutes: None,
+            epoch: 0,
+        }
+    }
+
+    /// pop and rock with an unplayed and a played file each, and a hidden
+    /// jazz category.
+    fn library() -> Library {
+        Library {
+            media_files: vec![
+                media_file("pop-new.mp4", "pop", 0),
+                media_file("pop-old.mp4", "pop", 3),
+                media_file("rock-new.mp4", "rock", 0),
+                media_file("rock-old.mp4", "rock", 2),
+                media_file("jazz.mp4", "jazz", 0),
+            ],
+            categories: vec![
+                category("pop", true),
+                category("rock", true),
+                category("jazz", false),
+            ],
+        }
+    }
+
+    fn picker(seed: u64) -> Picker {
+        Picker::new(StdRng::seed_from_u64(seed), &SelectionConfig::default())
+    }
+
+    fn after(path: &str, category: &str, times_chosen: u32) -> History {
+        History {
+            last_choice: Some(LastChoice {
+                media_file: media_file(path, category, 1),
+                times_chosen,
+                block_length: None,
+            }),
+        }
+    }
+
+    fn choose(
+        strategy: &dyn SelectionStrategy,
+        library: &Library,
+        history: &History,
+        current: Option<&MediaFile>,
+        seed: u64,
+    ) -> Option<String> {
+        strategy
+            .choose(library, history, current, &mut picker(seed))
+            .map(|f| f.path.display().to_string())
+    }
+
+    #[test]
+    fn category_blocks_keep_the_category_until_the_block_is_over() {
+        let library = library();
+        let current = media_file("pop-old.mp4", "pop", 3);
+
+        for seed in 0..20 {
+            let history = after("pop-old.mp4", "pop", 3);
+            assert_eq!(
+                choose(&CategoryBlocks, &library, &history, Some(&current), seed).as_deref(),
+                Some("pop-new.mp4")
+            );
+
+            // The default block lasts four tracks, then another visible
+            // category follows
+            let history = after("pop-old.mp4", "pop", 4);
+            assert_eq!(
+                choose(&CategoryBlocks, &library, &history, Some(&current), seed).as_deref(),
+                Some("rock-new.mp4")
+            );
+        }
+    }
+
+    #[test]
+    fn category_blocks_start_in_a_visible_category() {
+        let library = library();
+
+        for seed in 0..20 {
+            let chosen = choose(&CategoryBlocks, &library, &History::default(), None, seed);
+            assert!(
+                matches!(chosen.as_deref(), Some("pop-new.mp4" | "rock-new.mp4")),
+                "{chosen:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn category_blocks_fall_back_to_played_files_of_the_category() {
+        let library = library();
+        let history = after("pop-old.mp4", "pop", 1);
+        let current = media_file("pop-new.mp4", "pop", 0);
+
+        assert_eq!(
+            choose(&CategoryBlocks, &library, &history, Some(&current), 0).as_deref(),
+            Some("pop-old.mp4")
+        );
+
+        let library = Library {
+            media_files: vec![media_file("pop-new.mp4", "pop", 0)],
+            ..library
+        };
+        assert_eq!(
+            choose(&CategoryBlocks, &library, &history, Some(&current), 0),
+            None
+        );
+    }
+
+    #[test]
+    fn category_blocks_end_when_the_category_is_hidden() {
+        let library = library();
+        let history = after("jazz.mp4", "jazz", 1);
+
+        for seed in 0..20 {
+            let chosen = choose(&CategoryBlocks, &library, &history, None, seed);
+            assert!(
+                matches!(chosen.as_deref(), Some("pop-new.mp4" | "rock-new.mp4")),
+                "{chosen:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn least_played_chooses_among_the_least_played_visible_files() {
+        let library = Library {
+            media_files: vec![
+                media_file("a.mp4", "pop", 1),
+                media_file("b.mp4", "rock", 1),
+                media_file("c.mp4", "pop", 2),
+                media_file("d.mp4", "jazz", 0),
+                media_file("e.mp4", "rock", 0),
+            ],
+            ..library()
+        };
+        let current = media_file("e.mp4", "rock", 0);
+
+        for seed in 0..20 {
+            let chosen = choose(
+                &LeastPlayed,
+                &library,
+                &History::default(),
+                Some(&current),
+                seed,
+            );
+            assert!(
+                matches!(chosen.as_deref(), Some("a.mp4" | "b.mp4")),
+                "{chosen:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn weighted_random_prefers_files_played_less_often() {
+        let library = Library {
+            media_files: vec![
+                media_file("fresh.mp4", "pop", 0),
+                media_file("worn.mp4", "rock", 9),
+                media_file("hidden.mp4", "jazz", 0),
+                media_file("current.mp4", "pop", 0),
+            ],
+            ..library()
+        };
+        let current = media_file("current.mp4", "pop", 0);
+
+        let mut counts: HashMap<String, u32> = HashMap::new();
+        let mut picker = picker(7);
+        for _ in 0..1000 {
+            let chosen = WeightedRandom
+                .choose(&library, &History::default(), Some(&current), &mut picker)
+                .unwrap();
+            *counts.entry(chosen.path.display().to_string()).or_default() += 1;
+        }
+
+        assert_eq!(counts.len(), 2, "{counts:?}");
+        // 1 against 1/10
+        assert!(counts["fresh.mp4"] > 5 * counts["worn.mp4"], "{counts:?}");
+    }
+
+    #[test]
+    fn round_robin_takes_the_categories_in_turn() {
+        let library = Library {
+            categories: vec![
+                category("pop", true),
+                category("jazz", false),
+                category("rock", true),
+                category("empty", true),
+            ],
+            ..library()
+        };
+
+        let next = |history: &History| choose(&RoundRobin, &library, history, None, 0);
+        assert_eq!(next(&History::default()).as_deref(), Some("pop-new.mp4"));
+        assert_eq!(
+            next(&after("pop-new.mp4", "pop", 1)).as_deref(),
+            Some("rock-new.mp4")
+        );
+        // Past the category without files back to the first one
+        assert_eq!(
+            next(&after("rock-new.mp4", "rock", 1)).as_deref(),
+            Some("pop-new.mp4")
+        );
+    }
+
+    #[test]
+    fn category_weights_scale_the_choice_of_category() {
+        let categories = [category("pop", true), category("rock", true)];
+        let mut picker = picker(3).with_category_weights(HashMap::from([
+            ("pop".to_string(), 0.),
+            ("rock".to_string(), 2.),
+        ]));
+
+        for _ in 0..20 {
+            let chosen = picker.pick_category(categories.iter()).unwrap();
+            assert_eq!(chosen.category, "rock");
+        }
+        assert!(picker.pick_category(std::iter::empty()).is_none());
+    }
+
+    /// a to d played at 1000, 2000, 3000 and 4000 s, e never.
+    fn played_library() -> Library {
+        let played_at = |path: &str, last_played: Option<u64>| MediaFile {
+            last_played,
+            ..media_file(path, "pop", u32::from(last_played.is_some()))
+        };
+        Library {
+            media_files: vec![
+                played_at("a.mp4", Some(1000)),
+                played_at("b.mp4", Some(2000)),
+                played_at("c.mp4", Some(3000)),
+                played_at("d.mp4", Some(4000)),
+                played_at("e.mp4", None),
+            ],
+            ..library()
+        }
+    }
+
+    fn paths(library: &Library) -> Vec<String> {
+        library
+            .media_files
+            .iter()
+            .map(|f| f.path.display().to_string())
+            .collect()
+    }
+
+    fn not_recently_played(
+        tracks: Option<usize>,
+        hours: Option<f64>,
+        current: Option<&str>,
+    ) -> Vec<String> {
+        let library = played_library();
+        let current = current.and_then(|current| {
+            library
+                .media_files
+                .iter()
+                .find(|f| f.path.as_os_str() == current)
+                .cloned()
+        });
+
+        paths(&library.without_recently_played(
+            &NoRepeat { hours, tracks },
+            10_000,
+            current.as_ref(),
+        ))
+    }
+
+    #[test]
+    fn no_repeat_keeps_out_the_last_tracks() {
+        assert_eq!(
+            not_recently_played(Some(2), None, None),
+            ["a.mp4", "b.mp4", "e.mp4"]
+        );
+        // Fewer files played than the window: all of them
+        assert_eq!(not_recently_played(Some(10), None, None), ["e.mp4"]);
+        assert_eq!(not_recently_played(Some(0), None, None).len(), 5);
+        assert_eq!(not_recently_played(None, None, None).len(), 5);
+    }
+
+    #[test]
+    fn no_repeat_counts_the_track_on_air() {
+        // e is on air, so only d is among the last two besides it
+        assert_eq!(
+            not_recently_played(Some(2), None, Some("e.mp4")),
+            ["a.mp4", "b.mp4", "c.mp4", "e.mp4"]
+        );
+        assert_eq!(not_recently_played(Some(1), None, Some("e.mp4")).len(), 5);
+
+        // An earlier play of the track on air does not take up a place:
+        // besides d, c is the last track
+        assert_eq!(
+            not_recently_played(Some(2), None, Some("d.mp4")),
+            ["a.mp4", "b.mp4", "e.mp4"]
+        );
+    }
+
+    #[test]
+    fn no_repeat_keeps_out_the_last_hours() {
+        // Since 2800 s
+        assert_eq!(
+            not_recently_played(None, Some(2.), None),
+            ["a.mp4", "b.mp4", "e.mp4"]
+        );
+        assert_eq!(not_recently_played(None, Some(0.5), None).len(), 5);
+    }
+
+    #[test]
+    fn no_repeat_keeps_out_while_either_limit_holds() {
+        // One track since 4000 s, two hours since 2800 s
+        assert_eq!(
+            not_recently_played(Some(1), Some(2.), None),
+            ["a.mp4", "b.mp4", "e.mp4"]
+        );
+        // Three tracks since 2000 s, one hour since 6400 s
+        assert_eq!(
+            not_recently_played(Some(3), Some(1.), None),
+            ["a.mp4", "e.mp4"]
+        );
+    }
+
+    fn by(path: &str, artist: Option<&str>, last_played: Option<u64>) -> MediaFile {
+        MediaFile {
+            artist: artist.map(str::to_string),
+            last_played,
+            ..media_file(path, "pop", u32::from(last_played.is_some()))
+        }
+    }
+
+    /// Played in the order a, c, e; e without an artist.
+    fn artists_library() -> Library {
+        Library {
+            media_files: vec![
+                by("a.mp4", Some("Abba"), Some(1000)),
+                by("b.mp4", Some(" abba "), None),
+                by("c.mp4", Some("Queen"), Some(2000)),
+                by("d.mp4", Some("QUEEN"), None),
+                by("e.mp4", None, Some(3000)),
+                by("f.mp4", Some("Muse"), None),
+            ],
+            ..library()
+        }
+    }
+
+    fn without_recent_artists(tracks: usize, current: Option<&str>) -> Vec<String> {
+        let library = artists_library();
+        let current = current.and_then(|current| {
+            library
+                .media_files
+                .iter()
+                .find(|f| f.path.as_os_str() == current)
+                .cloned()
+        });
+
+        paths(&library.without_recent_artists(tracks, current.as_ref()))
+    }
+
+    #[test]
+    fn artists_of_the_last_tracks_are_left_out() {
+        // c on air, e before it: Queen in any spelling is out
+        assert_eq!(
+            without_recent_artists(2, Some("c.mp4")),
+            ["a.mp4", "b.mp4", "e.mp4", "f.mp4"]
+        );
+        // a before e: Abba as well
+        assert_eq!(without_recent_artists(3, Some("c.mp4")), ["e.mp4", "f.mp4"]);
+        assert_eq!(without_recent_artists(0, Some("c.mp4")).len(), 6);
+    }
+
+    #[test]
+    fn track_without_an_artist_takes_up_a_place() {
+        // e on air, c before it
+        assert_eq!(
+            without_recent_artists(2, Some("e.mp4")),
+            ["a.mp4", "b.mp4", "e.mp4", "f.mp4"]
+        );
+        assert_eq!(without_recent_artists(3, Some("e.mp4")), ["e.mp4", "f.mp4"]);
+        // Nothing on air, e was the last track
+        assert_eq!(without_recent_artists(1, None).len(), 6);
+        assert_eq!(
+            without_recent_artists(2, None),
+            ["a.mp4", "b.mp4", "e.mp4", "f.mp4"]
+        );
+    }
+
+    #[test]
+    fn same_artist_ignores_case_and_surrounding_whitespace() {
+        let same = |a: Option<&str>, b: Option<&str>| {
+            by("a.mp4", a, None).same_artist(&by("b.mp4", b, None))
+        };
+
+        assert!(same(Some("Abba"), Some(" ABBA ")));
+        assert!(!same(Some("Abba"), Some("Ab ba")));
+        assert!(!same(Some("Abba"), None));
+        assert!(!same(None, None));
+    }
+
+    fn with_airtime(name: &str, duration_overall: u64) -> Category {
+        Category {
+            duration_overall,
+            ..category(name, true)
+        }
+    }
+
+    /// How often each category is picked in `draws` draws with the airtime
+    /// targets.
+    fn airtime_counts(
+        categories: &[Category],
+        airtime: &[(&str, f64)],
+        draws: u32,
+    ) -> HashMap<String, u32> {
+        let config = SelectionConfig {
+            airtime: airtime
+                .iter()
+                .map(|&(category, target)| (category.to_string(), target))
+                .collect(),
+            ..Default::default()
+        };
+        let mut picker = Picker::new(StdRng::seed_from_u64(11), &config);
+
+        let mut counts = HashMap::new();
+        for _ in 0..draws {
+            let chosen = picker.pick_category(categories.iter()).unwrap();
+            *counts.entry(chosen.category.clone()).or_default() += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn category_behind_its_airtime_target_is_picked_more_often() {
+        // Same target, pop has had a fifth of the airtime of rock
+        let categories = [with_airtime("pop", 200), with_airtime("rock", 1000)];
+        let counts = airtime_counts(&categories, &[("pop", 50.), ("rock", 50.)], 1000);
+
+        assert!(counts["pop"] > 3 * counts["rock"], "{counts:?}");
+
+        // Both on target: even
+        let categories = [with_airtime("pop", 600), with_airtime("rock", 400)];
+        let counts = airtime_counts(&categories, &[("pop", 60.), ("rock", 40.)], 1000);
+
+        assert!((550..650).contains(&counts["pop"]), "{counts:?}");
+    }
+
+    #[test]
+    fn categories_without_a_target_are_left_out_when_others_have_one() {
+        let categories = [
+            with_airtime("pop", 1000),
+            with_airtime("rock", 0),
+            with_airtime("jazz", 0),
+        ];
+        let counts = airtime_counts(&categories, &[("pop", 1.)], 100);
+
+        assert_eq!(counts.get("pop"), Some(&100), "{counts:?}");
+
+        // Without any target all are equally likely
+        let counts = airtime_counts(&categories, &[], 900);
+        assert_eq!(counts.len(), 3, "{counts:?}");
+        assert!(
+            counts.values().all(|&n| (250..350).contains(&n)),
+            "{counts:?}"
+        );
+    }
+
+    #[test]
+    fn airtime_correction_is_clamped() {
+        // pop is 500000 times behind, but its weight only grows tenfold:
+        // 0.5 * 10 against 0.5 * 0.5 for rock, i.e. 20 to 1
+        let categories = [with_airtime("pop", 1), with_airtime("rock", 999_999)];
+        let counts = airtime_counts(&categories, &[("pop", 50.), ("rock", 50.)], 2100);
+
+        assert!((50..150).contains(&counts["rock"]), "{counts:?}");
+
+        // Never played is as far behind as possible
+        let categories = [with_airtime("pop", 0), with_airtime("rock", 1000)];
+        let counts = airtime_counts(&categories, &[("pop", 50.), ("rock", 50.)], 2100);
+
+        assert!((50..150).contains(&counts["rock"]), "{counts:?}");
+    }
+}
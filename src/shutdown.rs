@@ -0,0 +1,42 @@
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+
+/// Records that SIGINT or SIGTERM arrived, so the set can end gracefully
+/// instead of the process dying mid-track.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    /// Installs the handlers for SIGINT and SIGTERM. A second signal while
+    /// the first one is still being handled terminates the process at once.
+    pub fn install() -> io::Result<Self> {
+        let requested = Arc::new(AtomicBool::new(false));
+
+        for signal in [SIGINT, SIGTERM] {
+            // Registered first, so it only fires if the flag is already set
+            signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&requested))?;
+            signal_hook::flag::register(signal, Arc::clone(&requested))?;
+        }
+
+        Ok(Self { requested })
+    }
+
+    /// Ends the set as if a signal had arrived.
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// The raw flag, for loops that should give up waiting once it is set.
+    pub fn flag(&self) -> &AtomicBool {
+        &self.requested
+    }
+}
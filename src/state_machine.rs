@@ -1,198 +1,1087 @@
-use std::process::{Child, Command};
-
+use std::collections::VecDeque;
+use std::path::Path;
 use std::{thread, time::Duration};
 
-use serde_json::json;
-
-use crate::commands::{
-    get_duration, get_playback_time, quit, send_msg, start_video, wait_for_the_end,
-};
+use crate::commands::TrackEnd;
+use crate::config::Config;
+use crate::deck::{Backend, Deck, DeckPool, Recovery};
+use crate::energy::SetProgress;
+use crate::error::MpvError;
+use crate::fade::CrossfadeConfig;
 use crate::media_files::{self, MediaFile};
+use crate::shutdown::Shutdown;
+use crate::tempo::{self, TempoConfig};
 
-pub fn play() {
-    let mut from = 0;
-    let mut to = 1;
-    let mut children: Vec<Child> = Vec::with_capacity(2);
+/// What the mixer as a whole is doing. Decks are referred to by their index
+/// in the [`DeckPool`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MixerState {
+    /// Nothing is loaded yet, or the set is over.
+    Idle,
+    /// The first track is loading on `deck`.
+    Loading { deck: usize },
+    /// `deck` is on air while `next` loads the following track.
+    Preloading { deck: usize, next: usize },
+    /// `deck` is on air and `next` is ready to be faded in.
+    Playing { deck: usize, next: usize },
+    /// `from` fades out while `to` fades in.
+    Crossfading { from: usize, to: usize },
+    /// `to` is on air, `from` plays out its last seconds silently.
+    Draining { from: usize, to: usize },
+}
 
-    let mut media_file_from = get_next_song(None);
+/// Something that happened on the decks or on the clock.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MixerEvent {
+    /// The set begins.
+    Start,
+    /// A track was loaded, paused, on `deck`.
+    Loaded { deck: usize },
+    /// The track on `deck` reached the point where the next one mixes in.
+    MixPoint { deck: usize },
+    /// The track on `deck` ended.
+    FileEnded { deck: usize },
+    /// `deck` stopped answering.
+    DeckFailed { deck: usize },
+    /// `deck` was respawned and continues its track.
+    Resumed { deck: usize },
+    /// `deck` was respawned, but its track is gone.
+    Lost { deck: usize },
+    /// Timer tick while nothing else happened.
+    Tick,
+    /// The crossfade reached its end.
+    FadeDone,
+    /// The incoming deck could not be started, the crossfade is called off.
+    FadeAborted,
+    /// The set should end.
+    Stop,
+}
 
-    let mut socket_path_from = format!("/tmp/mpv{from}.socket");
-    let mut socket_path_to: String;
+/// Side effect requested by a transition, carried out by [`play`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    /// Choose the track after the one on `current` and preload it on `deck`.
+    Load { deck: usize, current: Option<usize> },
+    /// Unpause `deck` at the given level, see [`Deck::set_level`].
+    Play { deck: usize, level: f64 },
+    /// Start `to` silently and remember where the fade began.
+    BeginFade { from: usize, to: usize },
+    /// Move both volumes one step along the fade.
+    FadeStep { from: usize, to: usize },
+    /// Put both decks at their final volume.
+    FinishFade { from: usize, to: usize },
+    /// Put `from` back at full volume and stop `to`.
+    AbortFade { from: usize, to: usize },
+    /// Record the track on `deck` as played, followed by the one on `next`.
+    Report { deck: usize, next: usize },
+    /// Stop playback on `deck`.
+    StopDeck { deck: usize },
+    /// Respawn a failed deck.
+    Recover { deck: usize },
+    /// Fade out everything and quit.
+    Shutdown,
+}
 
-    let _ = std::fs::remove_file(&socket_path_from);
+impl MixerState {
+    /// The pure part of the mixer: the state after `event` and the actions
+    /// that get the decks there. Events that do not fit the current state
+    /// are ignored.
+    pub fn on(self, event: MixerEvent, decks: usize) -> (MixerState, Vec<Action>) {
+        let following = |deck: usize| (deck + 1) % decks;
 
-    let mut cmd = Command::new("mpv");
-    let cmd = cmd
-        .arg("--idle")
-        .arg("--no-terminal")
-        .arg("--quiet")
-        .arg("--fs")
-        .arg("--fs-screen=1")
-        .arg(format!("--input-ipc-server={}", socket_path_from));
+        match (self, event) {
+            (_, MixerEvent::Stop) => (MixerState::Idle, vec![Action::Shutdown]),
 
-    eprintln!("{cmd:#?}");
-    children.insert(from, cmd.spawn().expect("Failed to spawn mpv process"));
+            (MixerState::Idle, MixerEvent::Start) => (
+                MixerState::Loading { deck: 0 },
+                vec![Action::Load {
+                    deck: 0,
+                    current: None,
+                }],
+            ),
 
-    loop {
-        if std::path::Path::new(&socket_path_from).exists() {
-            break;
-        } else {
-            eprintln!("Cannot see IPC socket yet, waiting ...");
-            thread::sleep(Duration::from_millis(100));
+            (MixerState::Loading { deck }, MixerEvent::Loaded { deck: loaded })
+                if loaded == deck =>
+            {
+                let next = following(deck);
+                (
+                    MixerState::Preloading { deck, next },
+                    vec![
+                        Action::Play { deck, level: 100. },
+                        Action::Load {
+                            deck: next,
+                            current: Some(deck),
+                        },
+                    ],
+                )
+            }
+
+            (MixerState::Preloading { deck, next }, MixerEvent::Loaded { deck: loaded })
+                if loaded == next =>
+            {
+                (MixerState::Playing { deck, next }, vec![])
+            }
+
+            // Nothing could be loaded, try again
+            (MixerState::Loading { deck }, MixerEvent::Tick) => (
+                self,
+                vec![Action::Load {
+                    deck,
+                    current: None,
+                }],
+            ),
+            (MixerState::Preloading { deck, next }, MixerEvent::Tick) => (
+                self,
+                vec![Action::Load {
+                    deck: next,
+                    current: Some(deck),
+                }],
+            ),
+
+            // The track that should go on air did not start, the one loading
+            // on `next` takes its place
+            (MixerState::Preloading { deck, next }, MixerEvent::Lost { deck: lost })
+                if lost == deck =>
+            {
+                (
+                    MixerState::Loading { deck: next },
+                    vec![Action::StopDeck { deck }],
+                )
+            }
+
+            (MixerState::Playing { deck, next }, MixerEvent::MixPoint { deck: at })
+                if at == deck =>
+            {
+                (
+                    MixerState::Crossfading {
+                        from: deck,
+                        to: next,
+                    },
+                    vec![Action::BeginFade {
+                        from: deck,
+                        to: next,
+                    }],
+                )
+            }
+
+            // Without a mix point there is nothing to fade, cut to the next track
+            (
+                MixerState::Playing { deck, next },
+                MixerEvent::FileEnded { deck: ended } | MixerEvent::Lost { deck: ended },
+            ) if ended == deck => (
+                MixerState::Preloading {
+                    deck: next,
+                    next: deck,
+                },
+                vec![
+                    Action::Play {
+                        deck: next,
+                        level: 100.,
+                    },
+                    Action::Report { deck, next },
+                    Action::StopDeck { deck },
+                    Action::Load {
+                        deck,
+                        current: Some(next),
+                    },
+                ],
+            ),
+
+            (MixerState::Playing { deck, .. }, MixerEvent::DeckFailed { deck: failed })
+                if failed == deck =>
+            {
+                (self, vec![Action::Recover { deck }])
+            }
+
+            (MixerState::Crossfading { from, to }, MixerEvent::Tick) => {
+                (self, vec![Action::FadeStep { from, to }])
+            }
+
+            (MixerState::Crossfading { from, to }, MixerEvent::FadeDone) => (
+                MixerState::Draining { from, to },
+                vec![
+                    Action::FinishFade { from, to },
+                    Action::Report {
+                        deck: from,
+                        next: to,
+                    },
+                ],
+            ),
+
+            (MixerState::Crossfading { from, to }, MixerEvent::FadeAborted) => (
+                MixerState::Preloading {
+                    deck: from,
+                    next: to,
+                },
+                vec![
+                    Action::AbortFade { from, to },
+                    Action::Load {
+                        deck: to,
+                        current: Some(from),
+                    },
+                ],
+            ),
+
+            (MixerState::Draining { from, to }, MixerEvent::FileEnded { deck: ended })
+                if ended == from =>
+            {
+                (
+                    MixerState::Preloading {
+                        deck: to,
+                        next: from,
+                    },
+                    vec![
+                        Action::StopDeck { deck: from },
+                        Action::Load {
+                            deck: from,
+                            current: Some(to),
+                        },
+                    ],
+                )
+            }
+
+            _ => (self, vec![]),
         }
     }
+}
+
+/// The running crossfade.
+struct Fade {
+    /// Where it started, in playback time of the outgoing deck.
+    start: f64,
+    /// Speed of the incoming deck that matches the outgoing tempo.
+    nudge: Option<f64>,
+}
+
+/// Plays one track after another, mixed as `config` says, until `shutdown` is
+/// requested, then fades out over `fade_out` and quits all decks.
+///
+/// This is the impure half of the mixer: it waits for the next
+/// [`MixerEvent`], feeds it to [`MixerState::on`] and carries out the
+/// resulting actions.
+pub fn play(backend: &Backend, shutdown: &Shutdown, config: &Config, fade_out: Duration) {
+    let mut pool = DeckPool::start(backend, 2, &config.ipc).expect("Failed to start mpv decks");
 
-    let mut duration_from =
-        start_video(&socket_path_from, &media_file_from.path, 100).expect("Failed to start video");
-    eprintln!("duration_from: {}", duration_from);
+    let mut state = MixerState::Idle;
+    let mut events = VecDeque::from([MixerEvent::Start]);
+    let mut fade = None;
+    let mut set = SetProgress::start();
 
     loop {
-        wait_for_the_end(&socket_path_from);
+        let event = match events.pop_front() {
+            Some(event) => event,
+            None => wait_for_event(&mut pool, state, shutdown, &config.crossfade),
+        };
 
-        // Now time to start next video
-        let media_file_to = get_next_song(Some(media_file_from.clone()));
+        let (next_state, actions) = state.on(event, pool.len());
+        if next_state != state {
+            eprintln!("mixer: {state:?} -> {next_state:?} on {event:?}");
+        }
+        state = next_state;
 
-        socket_path_to = format!("/tmp/mpv{to}.socket");
-        let _ = std::fs::remove_file(&socket_path_to);
+        for action in actions {
+            if action == Action::Shutdown {
+                set.report();
+                end_set(pool, fade_out, &config.data_dir);
+                return;
+            }
+            events.extend(execute(&mut pool, action, &mut fade, &mut set, config));
+        }
+    }
+}
 
-        let mut cmd = Command::new("mpv");
-        let cmd = cmd
-            .arg("--idle")
-            .arg("--no-terminal")
-            .arg("--quiet")
-            .arg("--fs")
-            .arg(format!("--fs-screen={}", to + 1))
-            .arg(format!("--input-ipc-server={}", socket_path_to));
+/// Blocks until something relevant for `state` happens.
+fn wait_for_event(
+    pool: &mut DeckPool,
+    state: MixerState,
+    shutdown: &Shutdown,
+    crossfade: &CrossfadeConfig,
+) -> MixerEvent {
+    if shutdown.is_requested() {
+        return MixerEvent::Stop;
+    }
 
-        eprintln!("{cmd:#?}");
-        children.insert(to, cmd.spawn().expect("Failed to spawn mpv process"));
+    match state {
+        MixerState::Playing { deck, .. } => {
+            let on_air = pool.deck(deck);
+            let cue_out = on_air
+                .media_file
+                .as_ref()
+                .and_then(|media_file| media_file.cue_out);
+            match on_air
+                .client
+                .wait_for_the_end(crossfade.overlap, cue_out, shutdown.flag())
+            {
+                TrackEnd::TimeToMix => MixerEvent::MixPoint { deck },
+                TrackEnd::FileEnded => MixerEvent::FileEnded { deck },
+                TrackEnd::Disconnected => MixerEvent::DeckFailed { deck },
+                TrackEnd::Interrupted => MixerEvent::Stop,
+            }
+        }
+        MixerState::Draining { from, .. } => {
+            // Whatever follows the cue-out is not meant to be seen
+            if pool
+                .deck(from)
+                .media_file
+                .as_ref()
+                .is_some_and(|media_file| media_file.cue_out.is_some())
+            {
+                return MixerEvent::FileEnded { deck: from };
+            }
 
-        loop {
-            if std::path::Path::new(&socket_path_to).exists() {
-                break;
-            } else {
-                eprintln!("Cannot see IPC socket yet, waiting ...");
-                thread::sleep(Duration::from_millis(100));
+            eprintln!("Wait for old video to finish ...");
+            if !pool
+                .deck(from)
+                .client
+                .wait_for_end_file(Duration::from_secs(10))
+            {
+                eprintln!("Old video did not finish in time");
             }
+            MixerEvent::FileEnded { deck: from }
+        }
+        _ => {
+            thread::sleep(Duration::from_millis(500));
+            MixerEvent::Tick
         }
+    }
+}
 
-        let duration_to =
-            start_video(&socket_path_to, &media_file_to.path, 0).expect("Failed to start video");
-        eprintln!(
-            "Change from {} to {}.",
-            media_file_from.path.display(),
-            media_file_to.path.display()
-        );
+/// Carries out one action and returns the events it caused.
+fn execute(
+    pool: &mut DeckPool,
+    action: Action,
+    fade: &mut Option<Fade>,
+    set: &mut SetProgress,
+    config: &Config,
+) -> Vec<MixerEvent> {
+    match action {
+        Action::Load { deck, current } => {
+            pool.supervise();
+
+            // Get the next video ready while the current one is still playing
+            let current_media_file =
+                current.and_then(|current| pool.deck(current).media_file.clone());
+            let Some(duration) =
+                preload_next_song(pool, deck, current_media_file.as_ref(), set, config)
+            else {
+                eprintln!("Nothing could be loaded on deck {deck}, try again on the next tick");
+                return vec![];
+            };
+            eprintln!("duration on deck {deck}: {duration}");
+
+            vec![MixerEvent::Loaded { deck }]
+        }
+        Action::Play { deck, level } => {
+            if let Err(e) = pool.deck(deck).play(level) {
+                eprintln!("Cannot start deck {deck}: {e}");
+                pool.supervise();
+                if let Err(e) = pool.deck(deck).play(level) {
+                    eprintln!("Deck {deck} does not start after a restart either: {e}");
+                    return vec![MixerEvent::Lost { deck }];
+                }
+            }
+            record_on_air(pool.deck(deck), set, config);
+            vec![]
+        }
+        Action::BeginFade { from, to } => {
+            let (deck_from, deck_to) = pool.pair(from, to);
+            if let (Some(media_file_from), Some(media_file_to)) =
+                (&deck_from.media_file, &deck_to.media_file)
+            {
+                eprintln!(
+                    "Change from {} to {}.",
+                    media_file_from.path.display(),
+                    media_file_to.path.display()
+                );
+            }
+            eprintln!("Begin fading out of {from} and in of {to} ...");
+
+            let nudge = match_tempo(deck_from, deck_to, &config.tempo);
+
+            if let Err(e) = deck_to.play(0.) {
+                eprintln!("Cannot start deck {to}: {e}");
+                pool.supervise();
+                if let Err(e) = pool.deck(to).play(0.) {
+                    eprintln!(
+                        "Deck {to} does not start after a restart either: {e}, call off the fade"
+                    );
+                    return vec![MixerEvent::FadeAborted];
+                }
+            }
 
-        eprintln!("Begin fading out of {from} and in of {to} ...");
-
-        if let Some(playback_time) = get_playback_time(&socket_path_from) {
-            if let Some(duration) = get_duration(&socket_path_from) {
-                assert!(duration == duration_from);
-                let time_difference = duration - playback_time;
-
-                loop {
-                    // set volume of instance 0
-                    if let Some(playback_time) = get_playback_time(&socket_path_from) {
-                        if let Some(duration) = get_duration(&socket_path_from) {
-                            let volume = (duration - playback_time) * 100. / time_difference;
-
-                            eprintln!("set volume of instance {from}: {volume}");
-                            let msg =
-                                json!({ "command": ["set_property", "volume", volume.trunc()] });
-                            let _ = send_msg(&socket_path_from, msg);
-
-                            if volume < 40. {
-                                break;
-                            }
-
-                            // set volume of instance 1
-                            if let Some(playback_time) = get_playback_time(&socket_path_to) {
-                                let volume = playback_time * 100. / 10.;
-                                let volume = if volume <= 100. { volume } else { 100. };
-
-                                eprintln!("set volume of instance {to}: {volume}");
-                                let msg = json!({ "command": ["set_property", "volume", volume.trunc()] });
-                                let _ = send_msg(&socket_path_to, msg);
-                            } else {
-                                break;
-                            }
-
-                            thread::sleep(Duration::from_millis(500));
-                        } else {
-                            break;
+            let start = match pool.deck(from).client.get_playback_time() {
+                Ok(playback_time) => playback_time,
+                // mpv has no position once the outgoing file is over
+                Err(e) if e.is_property_unavailable() => return vec![MixerEvent::FadeDone],
+                Err(e) => {
+                    eprintln!("Instance {from} does not respond at the start of the fade: {e}");
+                    if unresponsive(&e) {
+                        let events = recover_fade(pool, from, to);
+                        if !events.is_empty() {
+                            return events;
                         }
-                    } else {
-                        break;
                     }
+                    match pool.deck(from).client.get_playback_time() {
+                        Ok(playback_time) => playback_time,
+                        Err(_) => return vec![MixerEvent::FadeDone],
+                    }
+                }
+            };
+            *fade = Some(Fade { start, nudge });
+            vec![MixerEvent::Tick]
+        }
+        Action::FadeStep { from, to } => {
+            let Some(Fade { start, nudge }) = *fade else {
+                return vec![MixerEvent::FadeDone];
+            };
+            let (deck_from, deck_to) = pool.pair(from, to);
+
+            // The outgoing deck is the clock for both curves
+            let elapsed = match deck_from.client.get_playback_time() {
+                Ok(playback_time) => playback_time - start,
+                Err(e) if e.is_property_unavailable() => {
+                    eprintln!("Stop fading, instance {from} is not playing anymore");
+                    return vec![MixerEvent::FadeDone];
                 }
+                Err(e) if unresponsive(&e) => {
+                    eprintln!("Instance {from} does not respond during the fade: {e}");
+                    return recover_fade(pool, from, to);
+                }
+                Err(e) => {
+                    eprintln!("No position on instance {from}, try again: {e}");
+                    return vec![];
+                }
+            };
+            let levels = config.crossfade.levels(elapsed);
+
+            eprintln!(
+                "{elapsed:.1}s into the fade, set level of instance {from}: {:.0}, of instance {to}: {:.0}",
+                levels.from, levels.to
+            );
+            let _ = deck_from.set_level(levels.from);
+            match deck_to.set_level(levels.to) {
+                Ok(()) => {}
+                Err(e) if unresponsive(&e) => {
+                    eprintln!("Instance {to} does not respond during the fade: {e}");
+                    return recover_fade(pool, from, to);
+                }
+                // Still loading, the next step sets it
+                Err(e) => eprintln!("Cannot set the level of instance {to} yet: {e}"),
+            }
+
+            if let Some(nudge) = nudge {
+                let speed = config.tempo.speed(nudge, elapsed, config.crossfade.fade_in);
+                let _ = deck_to.client.set_speed(speed);
+            }
+            let eased =
+                nudge.is_none_or(|_| elapsed >= config.crossfade.fade_in + config.tempo.ease);
+
+            if levels.done && eased {
+                vec![MixerEvent::FadeDone]
+            } else {
+                vec![]
+            }
+        }
+        Action::FinishFade { from, to } => {
+            *fade = None;
+            let (deck_from, deck_to) = pool.pair(from, to);
+            let _ = deck_from.set_level(0.);
+            // Its track was reported, whatever is left of it plays silently
+            deck_from.playing = false;
+            let _ = deck_to.set_level(100.);
+            let _ = deck_to.client.set_speed(1.);
+            record_on_air(deck_to, set, config);
+            vec![]
+        }
+        Action::AbortFade { from, to } => {
+            *fade = None;
+            let _ = pool.deck(from).set_level(100.);
+            let _ = pool.deck(to).stop();
+            vec![]
+        }
+        Action::Report { deck, next } => {
+            let (deck_played, deck_next) = pool.pair(deck, next);
+            let (Some(media_file_from), Some(media_file_to)) =
+                (&deck_played.media_file, &deck_next.media_file)
+            else {
+                return vec![];
+            };
+
+            // update CSV files
+            eprintln!(
+                "CATEGORY change from {} to {}: {}",
+                media_file_from.category,
+                media_file_to.category,
+                media_file_from.category != media_file_to.category
+            );
+            let cue_in = media_file_from.cue_in.unwrap_or(0.);
+            let cue_out = media_file_from.cue_out.unwrap_or(deck_played.duration);
+            match media_files::update_play_info(
+                &config.data_dir,
+                media_file_from,
+                (cue_out - cue_in).max(0.).round() as u64,
+                media_file_from.category != media_file_to.category,
+            ) {
+                Ok(_) => eprintln!("CSV files updated successfully"),
+                Err(e) => eprintln!("Failed to update CSV files: {e}"),
+            };
+            vec![]
+        }
+        Action::StopDeck { deck } => {
+            let _ = pool.deck(deck).stop();
+            vec![]
+        }
+        Action::Recover { deck } => {
+            // A deck that crashed while playing continues where it was
+            let resumed = pool.supervise().iter().any(|incident| {
+                incident.deck == deck && matches!(incident.recovery, Recovery::Resumed(_))
+            });
+            if resumed {
+                vec![MixerEvent::Resumed { deck }]
+            } else {
+                eprintln!("Track on deck {deck} is lost, change to the next one now");
+                vec![MixerEvent::Lost { deck }]
             }
         }
+        Action::Shutdown => vec![],
+    }
+}
 
-        let msg = json!({ "command": ["set_property", "volume", 0] });
-        let _ = send_msg(&socket_path_from, msg);
+/// The deck behind this error is dead or hanging, as opposed to merely not
+/// ready, e.g. while a file is still loading.
+fn unresponsive(e: &MpvError) -> bool {
+    e.is_fatal() || matches!(e, MpvError::Timeout { .. })
+}
 
-        let msg = json!({ "command": ["set_property", "volume", 100] });
-        let _ = send_msg(&socket_path_to, msg);
+/// Brings back the decks of a crossfade after one of them stopped answering.
+/// The fade goes on if both still have their tracks, ends early if the
+/// outgoing one is gone and is called off if the incoming one is.
+fn recover_fade(pool: &mut DeckPool, from: usize, to: usize) -> Vec<MixerEvent> {
+    let incidents = pool.supervise();
+    let lost = |deck| {
+        incidents.iter().any(|incident| {
+            incident.deck == deck && !matches!(incident.recovery, Recovery::Resumed(_))
+        })
+    };
 
-        // update CSV files
-        eprintln!(
-            "CATEGORY change from {} to {}: {}",
-            media_file_from.category,
-            media_file_to.category,
-            media_file_from.category != media_file_to.category
-        );
-        match media_files::update_play_info(
-            &media_file_from,
-            duration_from.round() as u64,
-            media_file_from.category != media_file_to.category,
-        ) {
+    if lost(to) {
+        vec![MixerEvent::FadeAborted]
+    } else if lost(from) {
+        vec![MixerEvent::FadeDone]
+    } else {
+        vec![]
+    }
+}
+
+/// Prepares the paused incoming deck for a beat-matched mix: nudges its speed
+/// to the outgoing tempo, puts it on its first beat after the cue-in and
+/// waits for the next beat of the outgoing deck. Returns the nudged speed.
+fn match_tempo(deck_from: &mut Deck, deck_to: &mut Deck, tempo: &TempoConfig) -> Option<f64> {
+    let (Some(media_file_from), Some(media_file_to)) = (&deck_from.media_file, &deck_to.media_file)
+    else {
+        return None;
+    };
+    let (Some(bpm_from), Some(bpm_to)) = (media_file_from.bpm, media_file_to.bpm) else {
+        return None;
+    };
+    let grids = media_file_from.first_beat.zip(media_file_to.first_beat);
+    let cue_in = media_file_to.cue_in.unwrap_or(0.);
+
+    let nudge = tempo.nudge(bpm_from, bpm_to);
+    if let Some(speed) = nudge {
+        eprintln!("Match {bpm_to:.1} BPM to {bpm_from:.1} BPM, speed {speed:.3}");
+        let _ = deck_to.client.set_property("audio-pitch-correction", true);
+        let _ = deck_to.client.set_speed(speed);
+    }
+
+    if tempo.align_beats
+        && let Some((first_beat_from, first_beat_to)) = grids
+    {
+        let _ = deck_to
+            .client
+            .set_playback_time(tempo::next_beat(bpm_to, first_beat_to, cue_in));
+
+        if let Ok(position) = deck_from.client.get_playback_time() {
+            let beat = tempo::next_beat(bpm_from, first_beat_from, position);
+            let speed = deck_from.client.get_speed().unwrap_or(1.);
+            thread::sleep(Duration::from_secs_f64((beat - position).max(0.) / speed));
+        }
+    }
+
+    nudge
+}
+
+/// Fades every deck on air out over `fade_out`, records how much of each
+/// track was played and quits all decks.
+fn end_set(mut pool: DeckPool, fade_out: Duration, data_dir: &Path) {
+    eprintln!("Shutdown requested, fading out over {fade_out:?} ...");
+
+    let on_air: Vec<(usize, f64)> = pool
+        .decks_mut()
+        .filter(|deck| deck.playing)
+        .map(|deck| (deck.index, deck.client.get_volume().unwrap_or(100.)))
+        .collect();
+
+    let steps = (fade_out.as_millis() / 100).max(1) as u32;
+    for step in 1..=steps {
+        let factor = 1. - f64::from(step) / f64::from(steps);
+        for &(index, volume) in &on_air {
+            let _ = pool
+                .deck(index)
+                .client
+                .set_volume((volume * factor).trunc());
+        }
+        thread::sleep(fade_out / steps);
+    }
+
+    for deck in pool.decks_mut().filter(|deck| deck.playing) {
+        let Some(media_file) = deck.media_file.clone() else {
+            continue;
+        };
+        let Ok(playback_time) = deck.client.get_playback_time() else {
+            continue;
+        };
+        let airtime = (playback_time - media_file.cue_in.unwrap_or(0.)).max(0.);
+
+        eprintln!("Played {airtime:.0}s of {}", media_file.path.display());
+        match media_files::update_play_info(data_dir, &media_file, airtime.round() as u64, false) {
             Ok(_) => eprintln!("CSV files updated successfully"),
             Err(e) => eprintln!("Failed to update CSV files: {e}"),
         };
+    }
 
-        let mut counter = 1;
-        loop {
-            if let Some(playback_time) = get_playback_time(&socket_path_from) {
-                if let Some(duration) = get_duration(&socket_path_from) {
-                    if playback_time < duration {
-                        eprintln!("Wait for old video to finish ...");
-                        thread::sleep(Duration::from_millis(500));
-
-                        if counter == 20 {
-                            break;
-                        }
+    pool.shutdown();
+    eprintln!("All decks are shut down");
+}
 
-                        counter += 1;
-                    } else {
-                        break;
-                    }
-                } else {
-                    break;
-                }
-            } else {
-                break;
+/// Chooses the next song and loads it paused on the given deck. A file that
+/// cannot be loaded is skipped in favour of another one. Returns the
+/// duration of the loaded file, `None` if nothing could be loaded.
+fn preload_next_song(
+    pool: &mut DeckPool,
+    index: usize,
+    current_media_file: Option<&MediaFile>,
+    set: &SetProgress,
+    config: &Config,
+) -> Option<f64> {
+    for _ in 0..3 {
+        let Some(media_file) = get_next_song(current_media_file.cloned(), set, config) else {
+            continue;
+        };
+        match pool.deck(index).preload(&media_file) {
+            Ok(duration) => {
+                pool.deck(index)
+                    .set_gain(config.loudness.gain(&config.data_dir, &media_file.path));
+                return Some(duration);
+            }
+            Err(e) => {
+                eprintln!(
+                    "Cannot preload {} on deck {index}: {e}",
+                    media_file.path.display()
+                );
+                pool.supervise();
             }
         }
+    }
 
-        let _ = quit(&socket_path_from);
-        match children[from].kill() {
-            Ok(_) => eprintln!("Old process successfully killed."),
-            Err(e) => eprintln!("Old process could not be killed: {e}"),
-        };
+    eprintln!("Failed to preload a video on deck {index}");
+    None
+}
 
-        let tmp = from;
-        from = to;
-        to = tmp;
+fn get_next_song(
+    current_media_file: Option<MediaFile>,
+    set: &SetProgress,
+    config: &Config,
+) -> Option<MediaFile> {
+    let target_energy = config.selection.set_plan.target(set.minutes());
+    match crate::media_files::choose_media_file(
+        &config.data_dir,
+        current_media_file,
+        &config.selection,
+        target_energy,
+    ) {
+        Ok(Some(media_file)) => Some(media_file),
+        Ok(None) => {
+            eprintln!("Failed to choose randomly a file from the list of available files.");
+            None
+        }
+        Err(e) => {
+            eprintln!("Failed to get a media file from CSV files: {e}");
+            None
+        }
+    }
+}
 
-        socket_path_from = format!("/tmp/mpv{from}.socket");
-        duration_from = duration_to;
-        media_file_from = media_file_to;
+/// Counts the track on `deck` towards the set plan, now that it is on air.
+/// Tracks that were picked but never played do not count.
+fn record_on_air(deck: &Deck, set: &mut SetProgress, config: &Config) {
+    let Some(media_file) = &deck.media_file else {
+        return;
+    };
+    if let Some(target) = config.selection.set_plan.target(set.minutes()) {
+        set.record(target, media_file.energy);
     }
 }
 
-fn get_next_song(current_media_file: Option<MediaFile>) -> MediaFile {
-    crate::media_files::choose_media_file(current_media_file)
-        .expect("Failed to get a media file from CSV files.")
-        .expect("Failed to choose randomly a file from the list of available files.")
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::deck::{Backend, IpcConfig};
+    use crate::fake_mpv::{FakeMpv, FakeMpvOptions};
+    use crate::media_files::{CATEGORIES_CSV, MEDIA_FILES_CSV};
+
+    const DECKS: usize = 2;
+
+    /// Feeds `events` one after another and returns the final state with
+    /// the actions of the last transition.
+    fn run(state: MixerState, events: &[MixerEvent]) -> (MixerState, Vec<Action>) {
+        events
+            .iter()
+            .fold((state, vec![]), |(state, _), &event| state.on(event, DECKS))
+    }
+
+    #[test]
+    fn first_track_goes_on_air_while_the_second_loads() {
+        let (state, actions) = MixerState::Idle.on(MixerEvent::Start, DECKS);
+        assert_eq!(state, MixerState::Loading { deck: 0 });
+        assert_eq!(
+            actions,
+            [Action::Load {
+                deck: 0,
+                current: None
+            }]
+        );
+
+        let (state, actions) = state.on(MixerEvent::Loaded { deck: 0 }, DECKS);
+        assert_eq!(state, MixerState::Preloading { deck: 0, next: 1 });
+        assert_eq!(
+            actions,
+            [
+                Action::Play {
+                    deck: 0,
+                    level: 100.
+                },
+                Action::Load {
+                    deck: 1,
+                    current: Some(0)
+                },
+            ]
+        );
+
+        let (state, actions) = state.on(MixerEvent::Loaded { deck: 1 }, DECKS);
+        assert_eq!(state, MixerState::Playing { deck: 0, next: 1 });
+        assert!(actions.is_empty());
+    }
+
+    #[test]
+    fn loaded_on_the_wrong_deck_is_ignored() {
+        let state = MixerState::Preloading { deck: 0, next: 1 };
+        assert_eq!(
+            state.on(MixerEvent::Loaded { deck: 0 }, DECKS),
+            (state, vec![])
+        );
+    }
+
+    #[test]
+    fn mix_point_fades_over_and_drains_the_outgoing_deck() {
+        let playing = MixerState::Playing { deck: 0, next: 1 };
+
+        let (state, actions) = playing.on(MixerEvent::MixPoint { deck: 0 }, DECKS);
+        assert_eq!(state, MixerState::Crossfading { from: 0, to: 1 });
+        assert_eq!(actions, [Action::BeginFade { from: 0, to: 1 }]);
+
+        let (state, actions) = state.on(MixerEvent::Tick, DECKS);
+        assert_eq!(state, MixerState::Crossfading { from: 0, to: 1 });
+        assert_eq!(actions, [Action::FadeStep { from: 0, to: 1 }]);
+
+        let (state, actions) = state.on(MixerEvent::FadeDone, DECKS);
+        assert_eq!(state, MixerState::Draining { from: 0, to: 1 });
+        assert_eq!(
+            actions,
+            [
+                Action::FinishFade { from: 0, to: 1 },
+                Action::Report { deck: 0, next: 1 },
+            ]
+        );
+
+        let (state, actions) = state.on(MixerEvent::FileEnded { deck: 0 }, DECKS);
+        assert_eq!(state, MixerState::Preloading { deck: 1, next: 0 });
+        assert_eq!(
+            actions,
+            [
+                Action::StopDeck { deck: 0 },
+                Action::Load {
+                    deck: 0,
+                    current: Some(1)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn aborted_fade_keeps_the_outgoing_deck_on_air() {
+        let (state, actions) = run(
+            MixerState::Playing { deck: 0, next: 1 },
+            &[MixerEvent::MixPoint { deck: 0 }, MixerEvent::FadeAborted],
+        );
+        assert_eq!(state, MixerState::Preloading { deck: 0, next: 1 });
+        assert_eq!(
+            actions,
+            [
+                Action::AbortFade { from: 0, to: 1 },
+                Action::Load {
+                    deck: 1,
+                    current: Some(0)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn ended_or_lost_track_cuts_to_the_next_one() {
+        for event in [
+            MixerEvent::FileEnded { deck: 1 },
+            MixerEvent::Lost { deck: 1 },
+        ] {
+            let (state, actions) = MixerState::Playing { deck: 1, next: 0 }.on(event, DECKS);
+            assert_eq!(state, MixerState::Preloading { deck: 0, next: 1 });
+            assert_eq!(
+                actions,
+                [
+                    Action::Play {
+                        deck: 0,
+                        level: 100.
+                    },
+                    Action::Report { deck: 1, next: 0 },
+                    Action::StopDeck { deck: 1 },
+                    Action::Load {
+                        deck: 1,
+                        current: Some(0)
+                    },
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn events_of_the_deck_waiting_in_line_are_ignored() {
+        let playing = MixerState::Playing { deck: 0, next: 1 };
+        for event in [
+            MixerEvent::MixPoint { deck: 1 },
+            MixerEvent::FileEnded { deck: 1 },
+            MixerEvent::Lost { deck: 1 },
+            MixerEvent::DeckFailed { deck: 1 },
+        ] {
+            assert_eq!(playing.on(event, DECKS), (playing, vec![]));
+        }
+    }
+
+    #[test]
+    fn failed_deck_on_air_is_recovered() {
+        let playing = MixerState::Playing { deck: 0, next: 1 };
+        assert_eq!(
+            playing.on(MixerEvent::DeckFailed { deck: 0 }, DECKS),
+            (playing, vec![Action::Recover { deck: 0 }])
+        );
+    }
+
+    #[test]
+    fn track_that_does_not_start_gives_way_to_the_one_loading() {
+        let (state, actions) = run(
+            MixerState::Playing { deck: 0, next: 1 },
+            &[
+                MixerEvent::FileEnded { deck: 0 },
+                MixerEvent::Lost { deck: 1 },
+            ],
+        );
+        assert_eq!(state, MixerState::Loading { deck: 0 });
+        assert_eq!(actions, [Action::StopDeck { deck: 1 }]);
+
+        let (state, _) = state.on(MixerEvent::Loaded { deck: 0 }, DECKS);
+        assert_eq!(state, MixerState::Preloading { deck: 0, next: 1 });
+    }
+
+    #[test]
+    fn failed_load_is_retried_on_the_next_tick() {
+        let loading = MixerState::Loading { deck: 0 };
+        assert_eq!(
+            loading.on(MixerEvent::Tick, DECKS),
+            (
+                loading,
+                vec![Action::Load {
+                    deck: 0,
+                    current: None
+                }]
+            )
+        );
+
+        let preloading = MixerState::Preloading { deck: 0, next: 1 };
+        assert_eq!(
+            preloading.on(MixerEvent::Tick, DECKS),
+            (
+                preloading,
+                vec![Action::Load {
+                    deck: 1,
+                    current: Some(0)
+                }]
+            )
+        );
+    }
+
+    #[test]
+    fn stop_ends_the_set_from_any_state() {
+        for state in [
+            MixerState::Idle,
+            MixerState::Loading { deck: 0 },
+            MixerState::Preloading { deck: 0, next: 1 },
+            MixerState::Playing { deck: 0, next: 1 },
+            MixerState::Crossfading { from: 0, to: 1 },
+            MixerState::Draining { from: 0, to: 1 },
+        ] {
+            assert_eq!(
+                state.on(MixerEvent::Stop, DECKS),
+                (MixerState::Idle, vec![Action::Shutdown])
+            );
+        }
+    }
+
+    #[test]
+    fn decks_take_turns_around_the_pool() {
+        let (state, actions) =
+            MixerState::Loading { deck: 2 }.on(MixerEvent::Loaded { deck: 2 }, 3);
+        assert_eq!(state, MixerState::Preloading { deck: 2, next: 0 });
+        assert_eq!(
+            actions[1],
+            Action::Load {
+                deck: 0,
+                current: Some(2)
+            }
+        );
+    }
+
+    fn media_file(path: &str) -> MediaFile {
+        MediaFile {
+            path: PathBuf::from(path),
+            category: "pop".to_string(),
+            played: 0,
+            cue_in: None,
+            cue_out: None,
+            bpm: None,
+            first_beat: None,
+            key: None,
+            last_played: None,
+            energy: None,
+            artist: None,
+            title: None,
+            analyzed: false,
+            rating: None,
+        }
+    }
+
+    /// Every volume strictly between silence and full level, i.e. the steps
+    /// of a fade.
+    fn ramp(fake: &FakeMpv) -> Vec<(f64, f64)> {
+        fake.volume_log()
+            .into_iter()
+            .filter(|&(_, volume)| volume > 0. && volume < 100.)
+            .collect()
+    }
+
+    #[test]
+    fn plays_a_set_against_fake_decks() {
+        let dir = std::env::temp_dir().join(format!("mpv-dj-set-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let library = vec![
+            media_file("a.mp4"),
+            media_file("b.mp4"),
+            media_file("c.mp4"),
+        ];
+        media_files::write_media_files_to_csv(
+            &library,
+            &dir.join(MEDIA_FILES_CSV),
+            &dir.join(CATEGORIES_CSV),
+        )
+        .unwrap();
+
+        // Each track lasts five seconds, the first mix starts after about
+        // four and is over after about five, the second would start after
+        // about eight
+        let options = FakeMpvOptions {
+            speed: 8.,
+            default_duration: 40.,
+            durations: HashMap::new(),
+        };
+        let fakes = [0, 1].map(|index| {
+            let socket_path = dir.join(format!("mpv{index}.socket"));
+            FakeMpv::spawn(socket_path.to_str().unwrap(), options.clone()).unwrap()
+        });
+
+        let config = Config {
+            data_dir: dir.clone(),
+            ipc: IpcConfig {
+                socket_dir: dir.clone(),
+                ..Default::default()
+            },
+            crossfade: CrossfadeConfig {
+                overlap: 10.,
+                fade_out: 10.,
+                fade_in: 10.,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let shutdown = Shutdown::default();
+        let set = {
+            let shutdown = shutdown.clone();
+            thread::spawn(move || play(&Backend::Attach, &shutdown, &config, Duration::ZERO))
+        };
+        thread::sleep(Duration::from_millis(6500));
+        shutdown.request();
+        set.join().unwrap();
+
+        // The first track on deck 0, the second on deck 1, the third
+        // preloaded on deck 0 after the first was drained
+        let [first, third] = <[String; 2]>::try_from(fakes[0].loaded_files()).unwrap();
+        let [second] = <[String; 1]>::try_from(fakes[1].loaded_files()).unwrap();
+        assert!(first != second && second != third && third != first);
+
+        // The default curve is linear in volume: the outgoing deck falls from
+        // 100 at the mix point, 30 s in, to 0 ten seconds later, the
+        // incoming one rises from 0 to 100 in its first ten seconds. A step
+        // may be logged a little late on the fast clock of the fakes
+        let fade_out = ramp(&fakes[0]);
+        assert!(!fade_out.is_empty());
+        for (time_pos, volume) in fade_out {
+            assert!(
+                (volume - (100. * (40. - time_pos) / 10.).clamp(0., 100.)).abs() < 5.,
+                "{time_pos}: {volume}"
+            );
+        }
+        let fade_in = ramp(&fakes[1]);
+        assert!(!fade_in.is_empty());
+        for (time_pos, volume) in fade_in {
+            assert!(
+                (volume - (100. * time_pos / 10.).clamp(0., 100.)).abs() < 5.,
+                "{time_pos}: {volume}"
+            );
+        }
+
+        // The mixed-out track and the one on air at the end were counted
+        let played: HashMap<String, u32> = media_files::read_media_files(&dir)
+            .unwrap()
+            .into_iter()
+            .map(|media_file| (media_file.path.display().to_string(), media_file.played))
+            .collect();
+        assert_eq!(played[&first], 1);
+        assert_eq!(played[&second], 1);
+        assert_eq!(played[&third], 0);
+
+        drop(fakes);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,271 @@
+use serde::{Deserialize, Serialize};
+
+/// Sample rate the analysis decodes audio at. Beats live well below it.
+pub const SAMPLE_RATE: u32 = 11025;
+/// Samples per onset frame, about 12 ms.
+const HOP: usize = 128;
+/// Tempo range that is searched, in beats per minute.
+const MIN_BPM: f64 = 60.;
+const MAX_BPM: f64 = 180.;
+/// Tempo the search leans towards, so half and double tempo lose.
+const PREFERRED_BPM: f64 = 120.;
+
+/// Tempo and beat grid of a track.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tempo {
+    pub bpm: f64,
+    /// Position of the first beat in seconds; every following beat is
+    /// `60 / bpm` later.
+    pub first_beat: f64,
+}
+
+/// How the incoming track is matched to the outgoing one.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TempoConfig {
+    pub enabled: bool,
+    /// Largest speed change, as a fraction, that is applied to the incoming
+    /// deck. Tracks further apart are mixed without matching.
+    pub max_nudge: f64,
+    /// Seconds the incoming deck takes to return to its own tempo once it
+    /// faded in.
+    pub ease: f64,
+    /// Start the incoming track on a beat of the outgoing one.
+    pub align_beats: bool,
+}
+
+impl Default for TempoConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_nudge: 0.08,
+            ease: 10.,
+            align_beats: true,
+        }
+    }
+}
+
+impl TempoConfig {
+    /// The speed that makes `to` play at the tempo of `from`, if matching is
+    /// on and the nudge is small enough to go unnoticed.
+    pub fn nudge(&self, from: f64, to: f64) -> Option<f64> {
+        if !self.enabled || from <= 0. || to <= 0. {
+            return None;
+        }
+
+        let speed = from / to;
+        ((speed - 1.).abs() <= self.max_nudge).then_some(speed)
+    }
+
+    /// Speed of the incoming deck `elapsed` seconds into the fade: matched
+    /// while it fades in, then eased back to its own tempo.
+    pub fn speed(&self, nudge: f64, elapsed: f64, fade_in: f64) -> f64 {
+        let progress = if self.ease > 0. {
+            ((elapsed - fade_in) / self.ease).clamp(0., 1.)
+        } else {
+            1.
+        };
+
+        nudge + (1. - nudge) * progress
+    }
+}
+
+/// Position of the next beat at or after `position` on the grid.
+pub fn next_beat(bpm: f64, first_beat: f64, position: f64) -> f64 {
+    let period = 60. / bpm;
+    let beats = ((position - first_beat) / period).ceil().max(0.);
+
+    first_beat + beats * period
+}
+
+/// Estimates the tempo of mono audio at [`SAMPLE_RATE`]: onsets are rises in
+/// frame energy, the tempo is the beat period at which the onsets repeat best
+/// and the first beat is the phase at which they line up best.
+pub fn detect(samples: &[i16]) -> Option<Tempo> {
+    let onsets = onset_envelope(samples);
+    let frame_rate = f64::from(SAMPLE_RATE) / HOP as f64;
+
+    let min_lag = (frame_rate * 60. / MAX_BPM).floor() as usize;
+    let max_lag = (frame_rate * 60. / MIN_BPM).ceil() as usize;
+    if onsets.len() < max_lag * 4 {
+        return None;
+    }
+
+    let correlation = |lag: usize| -> f64 {
+        onsets
+            .iter()
+            .zip(&onsets[lag..])
+            .map(|(a, b)| a * b)
+            .sum::<f64>()
+            / (onsets.len() - lag) as f64
+    };
+    let weighted = |lag: usize| -> f64 {
+        let bpm = frame_rate * 60. / lag as f64;
+        let octaves = (bpm / PREFERRED_BPM).log2();
+        correlation(lag) * (-0.5 * (octaves / 0.9).powi(2)).exp()
+    };
+
+    let mut best = (min_lag..=max_lag).max_by(|&a, &b| weighted(a).total_cmp(&weighted(b)))?;
+    if correlation(best) <= 0. {
+        return None;
+    }
+
+    // Every other beat repeats just as well, so take double tempo if it is
+    // about as strong and still in range
+    let half = best / 2;
+    if half > min_lag
+        && let Some(double) =
+            (half - 1..=half + 1).max_by(|&a, &b| correlation(a).total_cmp(&correlation(b)))
+        && correlation(double) >= 0.9 * correlation(best)
+    {
+        best = double;
+    }
+
+    // A whole track of beats pins the period down far below one frame
+    let (period, phase) = (0..=100)
+        .map(|step| best as f64 - 1. + f64::from(step) * 0.02)
+        .map(|period| (period, best_phase(&onsets, period)))
+        .max_by(|(_, (_, a)), (_, (_, b))| a.total_cmp(b))
+        .map(|(period, (phase, _))| (period, phase))?;
+
+    Some(Tempo {
+        bpm: frame_rate * 60. / period,
+        // Onset frame `i` is the rise from frame `i` to frame `i + 1`
+        first_beat: (phase + 1) as f64 / frame_rate,
+    })
+}
+
+/// Positive changes of log energy from frame to frame.
+fn onset_envelope(samples: &[i16]) -> Vec<f64> {
+    let energies: Vec<f64> = samples
+        .chunks(HOP)
+        .map(|frame| {
+            let energy: f64 = frame.iter().map(|&s| f64::from(s).powi(2)).sum();
+            (1. + energy / frame.len() as f64).ln()
+        })
+        .collect();
+
+    let rises: Vec<f64> = energies
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).max(0.))
+        .collect();
+
+    // Smeared over three frames, so beats between two frames still line up
+    (0..rises.len())
+        .map(|i| {
+            let before = if i > 0 { rises[i - 1] } else { 0. };
+            let after = rises.get(i + 1).copied().unwrap_or(0.);
+            0.25 * before + 0.5 * rises[i] + 0.25 * after
+        })
+        .collect()
+}
+
+/// The phase, in frames, at which a grid of `period` frames catches the most
+/// onset strength, and that strength.
+fn best_phase(onsets: &[f64], period: f64) -> (usize, f64) {
+    (0..period.ceil() as usize)
+        .map(|phase| (phase, phase_strength(onsets, phase, period)))
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .unwrap_or((0, 0.))
+}
+
+/// How strongly the onsets line up on a grid starting at frame `phase`.
+fn phase_strength(onsets: &[f64], phase: usize, period: f64) -> f64 {
+    (0..)
+        .map(|beat| (phase as f64 + beat as f64 * period).round() as usize)
+        .take_while(|&frame| frame < onsets.len())
+        .map(|frame| onsets[frame])
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mono audio of `seconds` with a short burst of noise on every beat.
+    fn click_track(bpm: f64, first_beat: f64, seconds: f64) -> Vec<i16> {
+        let rate = f64::from(SAMPLE_RATE);
+        let mut samples = vec![0i16; (seconds * rate) as usize];
+        let click = (0.01 * rate) as usize;
+
+        let mut beat = first_beat;
+        while beat < seconds {
+            let start = (beat * rate) as usize;
+            for (i, sample) in samples.iter_mut().skip(start).take(click).enumerate() {
+                *sample = if i % 2 == 0 { 12000 } else { -12000 };
+            }
+            beat += 60. / bpm;
+        }
+
+        samples
+    }
+
+    #[test]
+    fn beats_lie_on_the_grid() {
+        // 120 BPM, a beat every half second from 0.25 s
+        assert_eq!(next_beat(120., 0.25, 0.), 0.25);
+        assert_eq!(next_beat(120., 0.25, 0.25), 0.25);
+        assert_eq!(next_beat(120., 0.25, 0.26), 0.75);
+        assert_eq!(next_beat(120., 0.25, 10.), 10.25);
+        assert_eq!(next_beat(120., 0.25, 10.25), 10.25);
+    }
+
+    #[test]
+    fn small_tempo_differences_are_matched() {
+        let config = TempoConfig::default();
+
+        assert_eq!(config.nudge(126., 120.), Some(1.05));
+        assert_eq!(config.nudge(120., 120.), Some(1.));
+        // More than 8 % apart
+        assert_eq!(config.nudge(140., 120.), None);
+        assert_eq!(config.nudge(100., 120.), None);
+        assert_eq!(config.nudge(0., 120.), None);
+
+        let off = TempoConfig {
+            enabled: false,
+            ..Default::default()
+        };
+        assert_eq!(off.nudge(126., 120.), None);
+    }
+
+    #[test]
+    fn matched_speed_eases_back_after_the_fade_in() {
+        let config = TempoConfig::default();
+
+        // Matched while fading in for 10 s, back to normal 10 s later
+        assert_eq!(config.speed(1.05, 0., 10.), 1.05);
+        assert_eq!(config.speed(1.05, 10., 10.), 1.05);
+        assert!((config.speed(1.05, 15., 10.) - 1.025).abs() < 1e-9);
+        assert_eq!(config.speed(1.05, 20., 10.), 1.);
+        assert_eq!(config.speed(1.05, 60., 10.), 1.);
+
+        let no_ease = TempoConfig {
+            ease: 0.,
+            ..Default::default()
+        };
+        assert_eq!(no_ease.speed(1.05, 0., 10.), 1.);
+    }
+
+    #[test]
+    fn tempo_of_a_click_track_is_found() {
+        for (bpm, first_beat) in [(120., 0.3), (128., 0.1), (96., 0.5), (150., 0.2)] {
+            let tempo = detect(&click_track(bpm, first_beat, 30.))
+                .unwrap_or_else(|| panic!("no tempo found at {bpm} BPM"));
+
+            assert!((tempo.bpm - bpm).abs() < 0.5, "{tempo:?} at {bpm} BPM");
+            // Within two onset frames of a beat
+            let period = 60. / bpm;
+            let offset = (tempo.first_beat - first_beat).rem_euclid(period);
+            assert!(
+                offset.min(period - offset) < 0.025,
+                "{tempo:?} for a first beat at {first_beat}"
+            );
+        }
+    }
+
+    #[test]
+    fn silence_and_short_audio_have_no_tempo() {
+        assert_eq!(detect(&vec![0; SAMPLE_RATE as usize * 30]), None);
+        assert_eq!(detect(&click_track(120., 0., 2.)), None);
+    }
+}
@@ -0,0 +1,136 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+/// Byte stream the IPC client talks JSON lines over.
+///
+/// A read that hits the read timeout has to fail with
+/// [`io::ErrorKind::WouldBlock`] or [`io::ErrorKind::TimedOut`], like the
+/// standard library sockets do.
+pub trait Transport: Read + Write {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+/// Whichever transport a deck was configured with.
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_write_timeout(timeout)
+    }
+}
+
+/// The local socket given to `mpv --input-ipc-server`.
+impl Transport for UnixStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+/// An mpv on another machine, with its IPC socket bridged to TCP, e.g. by
+/// `socat TCP-LISTEN:9000,fork UNIX-CONNECT:/tmp/mpv0.socket`.
+impl Transport for TcpStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+/// One end of an in-process channel pair, so the protocol can be exercised
+/// without any sockets.
+pub struct MemoryTransport {
+    incoming: Receiver<Vec<u8>>,
+    outgoing: Sender<Vec<u8>>,
+    pending: Vec<u8>,
+    read_timeout: Option<Duration>,
+}
+
+impl MemoryTransport {
+    /// Two connected ends: what is written to one can be read from the other.
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        let (a_sender, a_receiver) = mpsc::channel();
+        let (b_sender, b_receiver) = mpsc::channel();
+
+        (
+            MemoryTransport {
+                incoming: a_receiver,
+                outgoing: b_sender,
+                pending: Vec::new(),
+                read_timeout: None,
+            },
+            MemoryTransport {
+                incoming: b_receiver,
+                outgoing: a_sender,
+                pending: Vec::new(),
+                read_timeout: None,
+            },
+        )
+    }
+}
+
+impl Read for MemoryTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            let received = match self.read_timeout {
+                Some(timeout) => self.incoming.recv_timeout(timeout),
+                None => self
+                    .incoming
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            match received {
+                Ok(bytes) => self.pending = bytes,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "read timed out"));
+                }
+                // The other end is gone, which reads like a closed socket
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+
+        Ok(n)
+    }
+}
+
+impl Write for MemoryTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outgoing
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "other end is gone"))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    fn set_write_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+        // Sending on a channel never blocks
+        Ok(())
+    }
+}